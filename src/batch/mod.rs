@@ -0,0 +1,153 @@
+use super::commands::{
+    commands::parse_klee_calc_options, debug_event::DebugEvent, debug_request::DebugRequest,
+    debug_response::DebugResponse, Command,
+};
+use super::debugger::kleecalc::{KleeCalcOptions, KtestResult, KtestStatus};
+use super::debugger::DebugHandler;
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::info;
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+
+// Bits of the exit status of a batch run that finished, they are or:ed together
+pub const EXIT_SKIPPED: i32 = 2;
+pub const EXIT_FAULTED: i32 = 4;
+pub const EXIT_OVER_BUDGET: i32 = 8;
+pub const EXIT_ERROR: i32 = 16;
+
+#[derive(Debug)]
+pub struct BatchConfig {
+    pub elf_file_path: PathBuf,
+    pub chip: String,
+    pub probe_num: usize,
+    pub ktests_directory: PathBuf,
+    pub output: PathBuf,
+    pub flash: bool,
+    // kleecalc options, as given to the kleecalc command
+    pub klee_calc_args: Vec<String>,
+}
+
+// Run kleecalc on all ktests without the prompt and return the exit status of the run
+pub fn batch_mode(config: BatchConfig) -> Result<i32> {
+    let args: Vec<&str> = config.klee_calc_args.iter().map(|a| a.as_str()).collect();
+    let mut options = parse_klee_calc_options(&args)?;
+    options.output = Some(config.output.clone());
+
+    let (sender_to_batch, batch_receiver): (Sender<Command>, Receiver<Command>) = unbounded();
+    let (sender_to_debugger, debug_receiver): (Sender<DebugRequest>, Receiver<DebugRequest>) =
+        unbounded();
+
+    let debugger_th = thread::spawn(move || -> Result<()> {
+        let mut debugger = DebugHandler::new_default();
+        debugger.run(sender_to_batch, debug_receiver)
+    });
+
+    let status = run_klee_calc(config, options, &sender_to_debugger, &batch_receiver);
+    if status.is_err() {
+        // Fails if the debugger already stopped
+        let _ = sender_to_debugger.send(DebugRequest::Exit);
+    }
+    let debugger_result = debugger_th
+        .join()
+        .map_err(|_| anyhow!("The debugger thread panicked"))?;
+    // An error of the debugger is what made the requests fail
+    debugger_result?;
+    let status = status?;
+
+    info!("kleecalc exit status {}", status);
+    Ok(status)
+}
+
+// Configure the debugger, run kleecalc and stop the debugger
+fn run_klee_calc(
+    config: BatchConfig,
+    options: KleeCalcOptions,
+    sender_to_debugger: &Sender<DebugRequest>,
+    batch_receiver: &Receiver<Command>,
+) -> Result<i32> {
+    let mut requests = vec![
+        DebugRequest::SetBinary {
+            path: config.elf_file_path,
+        },
+        DebugRequest::SetChip { chip: config.chip },
+        DebugRequest::SetProbeNumber {
+            number: config.probe_num,
+        },
+        DebugRequest::SetCWD {
+            cwd: env::current_dir()?.display().to_string(),
+        },
+        DebugRequest::SetKtestFolder {
+            cwd: config.ktests_directory.display().to_string(),
+        },
+    ];
+    if config.flash {
+        requests.push(DebugRequest::Flash {
+            reset_and_halt: true,
+        });
+    }
+    for request in requests {
+        sender_to_debugger.send(request)?;
+        wait_for_response(batch_receiver)?;
+    }
+
+    sender_to_debugger.send(DebugRequest::KleeCalc { options })?;
+    let status = loop {
+        match batch_receiver.recv()? {
+            Command::Response(DebugResponse::Error { message }) => {
+                return Err(anyhow!("kleecalc failed: {}", message))
+            }
+            Command::Event(DebugEvent::KleeCalcFinished {
+                results_path,
+                results,
+                error,
+            }) => {
+                if let Some(path) = results_path {
+                    println!("Results written to {}", path.display());
+                }
+                if let Some(error) = error {
+                    return Err(anyhow!("kleecalc stopped early: {}", error));
+                }
+                break exit_status(&results);
+            }
+            _ => (),
+        };
+    };
+
+    sender_to_debugger.send(DebugRequest::Exit)?;
+    loop {
+        if let Command::Response(DebugResponse::Exit) = batch_receiver.recv()? {
+            break;
+        }
+    }
+    Ok(status)
+}
+
+fn wait_for_response(receiver: &Receiver<Command>) -> Result<()> {
+    loop {
+        match receiver.recv()? {
+            Command::Response(DebugResponse::Error { message }) => return Err(anyhow!(message)),
+            Command::Response(_) => return Ok(()),
+            _ => (),
+        };
+    }
+}
+
+// Exit status bits of the ktests that were not cleanly measured
+pub fn exit_status(results: &[KtestResult]) -> i32 {
+    let mut status = 0;
+    for result in results {
+        status |= match result.status {
+            KtestStatus::Measured => 0,
+            KtestStatus::Skipped => EXIT_SKIPPED,
+            KtestStatus::Faulted => EXIT_FAULTED,
+            KtestStatus::Error => EXIT_ERROR,
+            KtestStatus::Hang => EXIT_OVER_BUDGET,
+        };
+        if result.over_budget {
+            status |= EXIT_OVER_BUDGET;
+        }
+    }
+    status
+}
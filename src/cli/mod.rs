@@ -5,6 +5,7 @@ use super::{
     },
     debugger::DebugHandler,
 };
+use crate::debugger::kleecalc::{to_hex, KtestObject};
 use crate::debugger::StackFrame;
 use crate::debugger::Variable;
 use anyhow::{anyhow, Result};
@@ -13,6 +14,7 @@ use debugserver_types::Breakpoint;
 use log::error;
 use probe_rs::{CoreStatus, HaltReason};
 use rustyline::Editor;
+use std::path::PathBuf;
 use std::thread;
 
 pub fn debug_mode(opt: super::Opt) -> Result<()> {
@@ -143,6 +145,14 @@ impl Cli {
                 reason,
                 hit_breakpoint_ids: _,
             } => self.handle_halted_event(pc, reason),
+            DebugEvent::BkptRecord { record } => println!(
+                "{} at {:#010x}: {} = {}",
+                record.handler, record.pc, record.name, record.value
+            ),
+            // the results are printed by the debugger
+            DebugEvent::KleeCalcStarted { .. }
+            | DebugEvent::KtestFinished { .. }
+            | DebugEvent::KleeCalcFinished { .. } => (),
         };
     }
 
@@ -198,6 +208,10 @@ impl Cli {
                 return Err(anyhow!("Unreachable"));
             }
             DebugResponse::SetKtestFolder => self.handle_set_ktest_direc_response(),
+            DebugResponse::KleeCalcDiff { report, regression } => {
+                self.handle_klee_calc_diff_response(report, regression)
+            }
+            DebugResponse::Ktest { path, objects } => self.handle_ktest_response(path, objects),
         };
 
         Ok(false)
@@ -402,4 +416,27 @@ impl Cli {
     fn handle_set_ktest_direc_response(&self) {
         println!("Current ktests directory set");
     }
+
+    fn handle_ktest_response(
+        &self,
+        path: PathBuf,
+        objects: Vec<(KtestObject, Option<Variable>)>,
+    ) {
+        println!("{}:", path.display());
+        for (object, value) in &objects {
+            println!(
+                "\t{}: {} bytes, 0x{}",
+                object.name,
+                object.bytes.len(),
+                to_hex(&object.bytes)
+            );
+            if let Some(value) = value {
+                println!("\t\t= {}", value.value_to_string());
+            }
+        }
+    }
+
+    fn handle_klee_calc_diff_response(&self, report: String, _regression: bool) {
+        println!("{}", report);
+    }
 }
@@ -1,8 +1,13 @@
 use super::{debug_request::DebugRequest, Command};
+use crate::debugger::bkpt_protocol::parse_bkpt_mapping;
+use crate::debugger::kleecalc::KleeCalcOptions;
+use crate::debugger::kleecalc_diff::Threshold;
+use crate::debugger::ktest::KtestCommand;
 
 use anyhow::{anyhow, Result};
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 struct CommandInfo {
     pub name: &'static str,
@@ -237,8 +242,45 @@ impl Commands {
                 CommandInfo {
                     name: "kleecalc",
                     description:
-                        "Computes cycles from a bkpt#2 and ending with a bkpt#1, also exchanges klee values with values in .ktest files",
-                    parser: |_args| Ok(DebugRequest::KleeCalc),
+                        "Computes cycles from a bkpt#2 and ending with a bkpt#1, also exchanges klee values with values in .ktest files. Labelled regions start with a bkpt#4 and end with a bkpt#5, with the label in r0. Options: --reset-cycle-counter, --reset-target, --snapshot, --resume <results file>, --output <results file>, --verify-errors, --timeout <seconds>, --cycle-budget <cycles>, --calibrate, --stack-usage, --repeat <n>, --include <glob>, --exclude <glob>, --from <ktest>, --to <ktest>, --bkpt-config <file>, --bkpt <nr>=<start|stop|inject|region-start|region-end|checkpoint:<name>|halt|ignore>",
+                    parser: |args| {
+                        Ok(DebugRequest::KleeCalc {
+                            options: parse_klee_calc_options(args)?,
+                        })
+                    },
+                },
+                CommandInfo {
+                    name: "kleecalc-diff",
+                    description: "Compares two kleecalc result files (.cycles or .json) by ktest name and reports slower ktests. Usage: kleecalc-diff <old> <new> [--threshold <cycles>|<percent>%]",
+                    parser: |args| {
+                        if args.len() < 2 {
+                            return Err(anyhow!("Requires the old and the new result file"));
+                        }
+                        let mut threshold = Threshold::default();
+                        let mut options = args[2..].iter();
+                        while let Some(arg) = options.next() {
+                            match *arg {
+                                "--threshold" => {
+                                    threshold = option_value(arg, options.next())?.parse()?
+                                }
+                                _ => return Err(anyhow!("Unknown kleecalc-diff option '{}'", arg)),
+                            };
+                        }
+                        Ok(DebugRequest::KleeCalcDiff {
+                            old: PathBuf::from(args[0]),
+                            new: PathBuf::from(args[1]),
+                            threshold,
+                        })
+                    },
+                },
+                CommandInfo {
+                    name: "ktest",
+                    description: "Creates, edits and prints .ktest files, relative paths are in the ktests directory. Usage: ktest new <file> <variable>..., ktest set <file> <object> <integer>|0x<hex bytes>, ktest show <file>",
+                    parser: |args| {
+                        Ok(DebugRequest::Ktest {
+                            command: parse_ktest_command(args)?,
+                        })
+                    },
                 },
             ],
         }
@@ -296,3 +338,87 @@ fn parse_bool(s: &str) -> Result<bool> {
         _ => Err(anyhow!("Expected a boolean argument")),
     }
 }
+
+pub fn parse_klee_calc_options(args: &[&str]) -> Result<KleeCalcOptions> {
+    let mut options = KleeCalcOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--reset-cycle-counter" => options.reset_cycle_counter = true,
+            "--reset-target" => options.reset_target = true,
+            "--snapshot" => options.snapshot = true,
+            "--verify-errors" => options.verify_errors = true,
+            "--timeout" => {
+                let seconds: f64 = option_value(arg, args.next())?.parse()?;
+                if !seconds.is_finite() || seconds <= 0.0 {
+                    return Err(anyhow!("--timeout requires a positive number of seconds"));
+                }
+                options.timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--cycle-budget" => {
+                options.cycle_budget = Some(option_value(arg, args.next())?.parse()?)
+            }
+            "--resume" => options.resume = Some(PathBuf::from(option_value(arg, args.next())?)),
+            "--output" => options.output = Some(PathBuf::from(option_value(arg, args.next())?)),
+            "--calibrate" => options.calibrate = true,
+            "--stack-usage" => options.stack_usage = true,
+            "--repeat" => {
+                options.repeat = parse_u32_from_str(option_value(arg, args.next())?)? as usize;
+                if options.repeat == 0 {
+                    return Err(anyhow!("--repeat requires a number larger than 0"));
+                }
+            }
+            "--include" => options
+                .include
+                .push(option_value(arg, args.next())?.to_owned()),
+            "--exclude" => options
+                .exclude
+                .push(option_value(arg, args.next())?.to_owned()),
+            "--from" => options.from = Some(ktest_name(option_value(arg, args.next())?)),
+            "--to" => options.to = Some(ktest_name(option_value(arg, args.next())?)),
+            "--bkpt-config" => {
+                options.bkpt_config = Some(PathBuf::from(option_value(arg, args.next())?))
+            }
+            "--bkpt" => options
+                .bkpt_actions
+                .push(parse_bkpt_mapping(option_value(arg, args.next())?)?),
+            _ => return Err(anyhow!("Unknown kleecalc option '{}'", arg)),
+        };
+    }
+    Ok(options)
+}
+
+fn parse_ktest_command(args: &[&str]) -> Result<KtestCommand> {
+    match args {
+        ["new", path, variables @ ..] if !variables.is_empty() => Ok(KtestCommand::New {
+            path: PathBuf::from(path),
+            variables: variables.iter().map(|v| v.to_string()).collect(),
+        }),
+        ["set", path, object, value] => Ok(KtestCommand::Set {
+            path: PathBuf::from(path),
+            object: object.to_string(),
+            value: value.to_string(),
+        }),
+        ["show", path] => Ok(KtestCommand::Show {
+            path: PathBuf::from(path),
+        }),
+        _ => Err(anyhow!(
+            "Expected ktest new <file> <variable>..., ktest set <file> <object> <value> or ktest show <file>"
+        )),
+    }
+}
+
+// A ktest number is short for the file name KLEE gives it, e.g. 42 is test000042.ktest
+fn ktest_name(s: &str) -> String {
+    match s.parse::<u32>() {
+        Ok(nr) => format!("test{:06}.ktest", nr),
+        Err(_) => s.to_owned(),
+    }
+}
+
+fn option_value<'a>(option: &str, value: Option<&&'a str>) -> Result<&'a str> {
+    match value {
+        Some(value) => Ok(*value),
+        None => Err(anyhow!("{} requires a value", option)),
+    }
+}
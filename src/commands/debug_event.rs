@@ -1,4 +1,7 @@
+use crate::debugger::bkpt_handler::BkptRecord;
+use crate::debugger::kleecalc::KtestResult;
 use probe_rs::HaltReason;
+use std::path::PathBuf;
 
 
 //use debugserver_types::{
@@ -9,6 +12,25 @@ use probe_rs::HaltReason;
 #[derive(Debug, Clone)]
 pub enum DebugEvent {
     Halted { pc: u32, reason: HaltReason, hit_breakpoint_ids: Option<Vec<u32>> },
+    // A value recorded by a registered bkpt handler
+    BkptRecord {
+        record: BkptRecord,
+    },
+    KleeCalcStarted {
+        total: usize,
+    },
+    // A ktest result was written, `done` of the `total` ktests have been run
+    KtestFinished {
+        result: KtestResult,
+        done: usize,
+        total: usize,
+    },
+    // kleecalc stopped, `error` is set when it stopped before all ktests were run
+    KleeCalcFinished {
+        results_path: Option<PathBuf>,
+        results: Vec<KtestResult>,
+        error: Option<String>,
+    },
 }
 
 
@@ -2,6 +2,10 @@ use std::path::PathBuf;
 
 use debugserver_types::{Source, SourceBreakpoint};
 
+use crate::debugger::kleecalc::KleeCalcOptions;
+use crate::debugger::kleecalc_diff::Threshold;
+use crate::debugger::ktest::KtestCommand;
+
 #[derive(Debug, Clone)]
 pub enum DebugRequest {
     Attach {
@@ -68,5 +72,15 @@ pub enum DebugRequest {
         cwd: String,
     },
     CycleCounter,
-    KleeCalc,
+    KleeCalc {
+        options: KleeCalcOptions,
+    },
+    KleeCalcDiff {
+        old: PathBuf,
+        new: PathBuf,
+        threshold: Threshold,
+    },
+    Ktest {
+        command: KtestCommand,
+    },
 }
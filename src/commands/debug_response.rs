@@ -1,7 +1,9 @@
+use crate::debugger::kleecalc::KtestObject;
 use crate::debugger::StackFrame;
 use crate::debugger::Variable;
 use debugserver_types::Breakpoint;
 use probe_rs::CoreStatus;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub enum DebugResponse {
@@ -63,4 +65,13 @@ pub enum DebugResponse {
     DAPVariables {
         variables: Vec<Variable>,
     },
+    KleeCalcDiff {
+        report: String,
+        regression: bool,
+    },
+    Ktest {
+        path: PathBuf,
+        // The objects with their values decoded using the firmware types
+        objects: Vec<(KtestObject, Option<Variable>)>,
+    },
 }
@@ -24,10 +24,10 @@ use serde_json::{from_slice, from_value, json, to_vec};
 
 use super::{
     commands::{
-        debug_event::DebugEvent, debug_request::DebugRequest, debug_response::DebugResponse,
-        Command,
+        commands::parse_klee_calc_options, debug_event::DebugEvent, debug_request::DebugRequest,
+        debug_response::DebugResponse, Command,
     },
-    debugger::DebugHandler,
+    debugger::{kleecalc::KtestResult, DebugHandler},
 };
 
 use probe_rs::HaltReason;
@@ -156,6 +156,17 @@ impl<R: Read, W: Write> DebugAdapter<R, W> {
             // Check for events
             match self.receiver.try_recv() {
                 Ok(Command::Event(event)) => self.handle_event_command(event)?,
+                // An error that ended the debug session, e.g. while kleecalc was running
+                Ok(Command::Response(DebugResponse::Error { message })) => {
+                    error!("{}", message);
+                    self.send_event(
+                        "output",
+                        json!({
+                            "category": "stderr",
+                            "output": format!("{}\n", message),
+                        }),
+                    )?;
+                }
                 Ok(_) => error!("Unreachable"),
                 Err(_) => (),
             };
@@ -212,6 +223,7 @@ impl<R: Read, W: Write> DebugAdapter<R, W> {
             "stepIn" => self.handle_next_dap_request(&request), // TODO
             "stepOut" => self.handle_next_dap_request(&request), // TODO
             "evaluate" => self.handle_evaluate_dap_request(&request),
+            "kleecalc" => self.handle_klee_calc_dap_request(&request),
             _ => {
                 error!("command: {}", request.command);
                 Ok(false) // NOTE: Return Error maybe
@@ -275,11 +287,96 @@ impl<R: Read, W: Write> DebugAdapter<R, W> {
                     self.seq,
                 )?;
             }
+            DebugEvent::BkptRecord { record } => self.send_event(
+                "output",
+                json!({
+                    "category": "console",
+                    "output": format!(
+                        "{} at {:#010x}: {} = {}\n",
+                        record.handler, record.pc, record.name, record.value
+                    ),
+                }),
+            )?,
+            DebugEvent::KleeCalcStarted { total } => self.send_event(
+                "progressStart",
+                json!({
+                    "progressId": KLEE_CALC_PROGRESS_ID,
+                    "title": "kleecalc",
+                    "message": format!("0/{} ktests", total),
+                    "percentage": 0,
+                }),
+            )?,
+            DebugEvent::KtestFinished {
+                result,
+                done,
+                total,
+            } => self.send_event(
+                "progressUpdate",
+                json!({
+                    "progressId": KLEE_CALC_PROGRESS_ID,
+                    "message": format!(
+                        "{}/{} ktests, {}: {}",
+                        done,
+                        total,
+                        result.ktest,
+                        result.status.as_str()
+                    ),
+                    "percentage": match total {
+                        0 => 100,
+                        total => done * 100 / total,
+                    },
+                }),
+            )?,
+            DebugEvent::KleeCalcFinished {
+                results_path,
+                results,
+                error,
+            } => {
+                self.send_event(
+                    "progressEnd",
+                    json!({
+                        "progressId": KLEE_CALC_PROGRESS_ID,
+                        "message": match &error {
+                            Some(error) => format!("kleecalc stopped: {}", error),
+                            None => format!("{} ktests run", results.len()),
+                        },
+                    }),
+                )?;
+                self.send_event(
+                    "output",
+                    json!({
+                        "category": "console",
+                        "output": results_table(&results),
+                    }),
+                )?;
+                self.send_event(
+                    "kleecalcResults",
+                    json!({
+                        "resultsPath": results_path,
+                        "results": results,
+                        "error": error,
+                    }),
+                )?;
+            }
         };
 
         Ok(())
     }
 
+    fn send_event(&mut self, event: &str, body: serde_json::Value) -> Result<()> {
+        self.seq = send_data(
+            &mut self.writer,
+            &to_vec(&Event {
+                body: Some(body),
+                event: event.to_owned(),
+                seq: self.seq,
+                type_: "event".to_owned(),
+            })?,
+            self.seq,
+        )?;
+        Ok(())
+    }
+
     fn handle_launch_dap_request(&mut self, _request: &Request) -> Result<bool> {
         error!("Unimplemented");
         Ok(false) // NOTE: return error maybe
@@ -316,6 +413,16 @@ impl<R: Read, W: Write> DebugAdapter<R, W> {
             None => (),
         };
 
+        if let Some(ktests_directory) = args.ktests_directory {
+            // Set ktests directory
+            self.sender.send(DebugRequest::SetKtestFolder {
+                cwd: ktests_directory,
+            })?;
+
+            // Get DebugResponse
+            let _ack = self.retrieve_response()?;
+        }
+
         // Flash and attach or just attach to the core
         match args.flash {
             Some(true) => {
@@ -654,6 +761,45 @@ impl<R: Read, W: Write> DebugAdapter<R, W> {
         Ok(false)
     }
 
+    // Custom request that starts kleecalc, the progress and results are sent as events
+    fn handle_klee_calc_dap_request(&mut self, request: &Request) -> Result<bool> {
+        let args: KleeCalcRequestArguments = get_arguments(&request)?;
+        debug!("kleecalc args: {:?}", args);
+        let options: Vec<&str> = args.options.iter().map(|o| o.as_str()).collect();
+        let options = parse_klee_calc_options(&options)?;
+
+        // Set ktests directory, this ends an attached session so the directory given to
+        // attach is used when there is none
+        if let Some(ktests_directory) = args.ktests_directory {
+            self.sender.send(DebugRequest::SetKtestFolder {
+                cwd: ktests_directory,
+            })?;
+
+            // Get DebugResponse
+            let _ack = self.retrieve_response()?;
+        }
+
+        // Start kleecalc
+        self.sender.send(DebugRequest::KleeCalc { options })?;
+
+        // Get KleeCalc DebugResponse
+        let _ack = self.retrieve_response()?;
+
+        let response = Response {
+            body: None,
+            command: request.command.clone(),
+            message: None,
+            request_seq: request.seq,
+            seq: self.seq,
+            success: true,
+            type_: "response".to_string(),
+        };
+
+        self.seq = send_data(&mut self.writer, &to_vec(&response)?, self.seq)?;
+
+        Ok(false)
+    }
+
     fn handle_set_breakpoints_dap_request(&mut self, request: &Request) -> Result<bool> {
         let args: SetBreakpointsArguments = get_arguments(request)?;
         debug!("args: {:#?}", args);
@@ -840,6 +986,36 @@ struct AttachRequestArguments {
     reset: Option<bool>,
     halt_after_reset: Option<bool>,
     flash: Option<bool>,
+    ktests_directory: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct KleeCalcRequestArguments {
+    // Defaults to the ktests directory given to attach
+    #[serde(default)]
+    ktests_directory: Option<String>,
+    // Options as given to the kleecalc command, e.g. ["--snapshot", "--timeout", "2"]
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+const KLEE_CALC_PROGRESS_ID: &str = "kleecalc";
+
+// One line per ktest with its status and cycles
+fn results_table(results: &[KtestResult]) -> String {
+    let mut table = format!("{:<24} {:<10} {:>12}\n", "ktest", "status", "cycles");
+    for result in results {
+        table += &format!(
+            "{:<24} {:<10} {:>12}\n",
+            result.ktest,
+            result.status.as_str(),
+            match result.cycles {
+                Some(cycles) => cycles.to_string(),
+                None => "-".to_owned(),
+            }
+        );
+    }
+    table
 }
 
 #[derive(Deserialize, Debug, Default)]
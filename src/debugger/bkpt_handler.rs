@@ -0,0 +1,147 @@
+use super::kleecalc::read_c_string;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// What the debugger does after a bkpt handler has run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BkptOutcome {
+    // Step over the bkpt and let the core run
+    Resume,
+    // Leave the core halted and report the halt like any other
+    Halt,
+}
+
+// A value recorded by a bkpt handler
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BkptRecord {
+    pub handler: String,
+    pub pc: u32,
+    pub name: String,
+    pub value: String,
+}
+
+// Collects what the handlers record, the records are sent as events and added to the
+// result of the running ktest
+#[derive(Debug, Default)]
+pub struct ResultSink {
+    handler: String,
+    pc: u32,
+    records: Vec<BkptRecord>,
+}
+
+impl ResultSink {
+    pub fn new(handler: &str, pc: u32) -> ResultSink {
+        ResultSink {
+            handler: handler.to_owned(),
+            pc,
+            records: vec![],
+        }
+    }
+
+    pub fn record(&mut self, name: &str, value: impl ToString) {
+        self.records.push(BkptRecord {
+            handler: self.handler.clone(),
+            pc: self.pc,
+            name: name.to_owned(),
+            value: value.to_string(),
+        });
+    }
+
+    pub fn into_records(self) -> Vec<BkptRecord> {
+        self.records
+    }
+}
+
+// An on-target protocol driven by bkpt instructions with a given immediate
+pub trait BkptHandler {
+    fn name(&self) -> &str;
+
+    // Called with the core halted on the bkpt instruction at `pc`
+    fn handle(
+        &mut self,
+        core: &mut probe_rs::Core,
+        pc: u32,
+        sink: &mut ResultSink,
+    ) -> Result<BkptOutcome>;
+}
+
+// The bkpt handlers keyed by bkpt immediate, they are tried before trace mode and user halts
+#[derive(Default)]
+pub struct BkptHandlers {
+    handlers: HashMap<u8, Box<dyn BkptHandler>>,
+}
+
+impl BkptHandlers {
+    // Register a handler for a bkpt immediate, the handler it replaces is returned
+    pub fn register(
+        &mut self,
+        nr: u8,
+        handler: Box<dyn BkptHandler>,
+    ) -> Option<Box<dyn BkptHandler>> {
+        self.handlers.insert(nr, handler)
+    }
+
+    pub fn get_mut(&mut self, nr: u8) -> Option<&mut Box<dyn BkptHandler>> {
+        self.handlers.get_mut(&nr)
+    }
+}
+
+// bkpt #6: record a named value, r0 holds the address of the name and r1 the value
+pub const VALUE_BKPT: u8 = 6;
+// bkpt #7: assert, r0 holds the condition and r1 the address of a message
+pub const ASSERT_BKPT: u8 = 7;
+
+// Records a value from the firmware and resumes
+pub struct ValueHandler;
+
+impl BkptHandler for ValueHandler {
+    fn name(&self) -> &str {
+        "value"
+    }
+
+    fn handle(
+        &mut self,
+        core: &mut probe_rs::Core,
+        _pc: u32,
+        sink: &mut ResultSink,
+    ) -> Result<BkptOutcome> {
+        let name_address = core.read_core_reg(0u16)?;
+        let value = core.read_core_reg(1u16)?;
+        let name = read_c_string(core, name_address)?;
+        sink.record(&name, value);
+        Ok(BkptOutcome::Resume)
+    }
+}
+
+// Resumes if the condition holds, otherwise records the message and leaves the core halted
+pub struct AssertHandler;
+
+impl BkptHandler for AssertHandler {
+    fn name(&self) -> &str {
+        "assert"
+    }
+
+    fn handle(
+        &mut self,
+        core: &mut probe_rs::Core,
+        _pc: u32,
+        sink: &mut ResultSink,
+    ) -> Result<BkptOutcome> {
+        if core.read_core_reg(0u16)? != 0 {
+            return Ok(BkptOutcome::Resume);
+        }
+        let message_address = core.read_core_reg(1u16)?;
+        let message = read_c_string(core, message_address)?;
+        sink.record("assertion failed", message);
+        Ok(BkptOutcome::Halt)
+    }
+}
+
+// The handlers every debugger session starts with, register the crate's own protocols here
+pub fn default_bkpt_handlers() -> BkptHandlers {
+    let mut handlers = BkptHandlers::default();
+    handlers.register(VALUE_BKPT, Box::new(ValueHandler));
+    handlers.register(ASSERT_BKPT, Box::new(AssertHandler));
+    handlers
+}
@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// What trace mode does when the core halts on a bkpt instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum BkptAction {
+    // Start of a measurement
+    Start,
+    // End of a measurement
+    Stop,
+    // klee_make_symbolic call, r0 holds the address, r1 the size and r2 the name of the variable
+    Inject,
+    // Start and end of a labelled region, r0 holds the label
+    RegionStart,
+    RegionEnd,
+    // Record the cycles since the start of the measurement under the name
+    Checkpoint(String),
+    // Leave the core halted for the user, trace mode goes on when the core is continued
+    Halt,
+    // Continue as if nothing happened
+    Ignore,
+}
+
+impl FromStr for BkptAction {
+    type Err = anyhow::Error;
+
+    // The checkpoint name follows after a space or a colon, e.g. `checkpoint:isr`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().splitn(2, |c: char| c.is_whitespace() || c == ':');
+        let action = parts.next().unwrap_or_default();
+        let argument = parts.next().map(|a| a.trim()).filter(|a| !a.is_empty());
+        match (action, argument) {
+            ("start", None) => Ok(BkptAction::Start),
+            ("stop", None) => Ok(BkptAction::Stop),
+            ("inject", None) => Ok(BkptAction::Inject),
+            ("region-start", None) => Ok(BkptAction::RegionStart),
+            ("region-end", None) => Ok(BkptAction::RegionEnd),
+            ("checkpoint", Some(name)) => Ok(BkptAction::Checkpoint(name.to_owned())),
+            ("checkpoint", None) => Err(anyhow!("A checkpoint requires a name")),
+            ("halt", None) => Ok(BkptAction::Halt),
+            ("ignore", None) => Ok(BkptAction::Ignore),
+            _ => Err(anyhow!(
+                "Unknown bkpt action {:?}, expected start, stop, inject, region-start, region-end, checkpoint <name>, halt or ignore",
+                s.trim()
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for BkptAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BkptAction::Start => write!(f, "start"),
+            BkptAction::Stop => write!(f, "stop"),
+            BkptAction::Inject => write!(f, "inject"),
+            BkptAction::RegionStart => write!(f, "region-start"),
+            BkptAction::RegionEnd => write!(f, "region-end"),
+            BkptAction::Checkpoint(name) => write!(f, "checkpoint {}", name),
+            BkptAction::Halt => write!(f, "halt"),
+            BkptAction::Ignore => write!(f, "ignore"),
+        }
+    }
+}
+
+// Mapping from bkpt immediate to action, numbers without an action are ignored
+#[derive(Debug, Clone)]
+pub struct BkptProtocol {
+    actions: HashMap<u8, BkptAction>,
+}
+
+impl Default for BkptProtocol {
+    fn default() -> BkptProtocol {
+        let mut actions = HashMap::new();
+        actions.insert(1, BkptAction::Stop);
+        actions.insert(2, BkptAction::Start);
+        actions.insert(3, BkptAction::Inject);
+        actions.insert(4, BkptAction::RegionStart);
+        actions.insert(5, BkptAction::RegionEnd);
+        BkptProtocol { actions }
+    }
+}
+
+impl BkptProtocol {
+    pub fn action(&self, nr: u8) -> Option<&BkptAction> {
+        self.actions.get(&nr)
+    }
+
+    pub fn set(&mut self, nr: u8, action: BkptAction) {
+        self.actions.insert(nr, action);
+    }
+
+    // Add the mappings of a file with one `<bkpt number> = <action>` per line, # starts a comment
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (nr, action) = parse_bkpt_mapping(line)
+                .with_context(|| format!("{}:{}", path.display(), i + 1))?;
+            self.set(nr, action);
+        }
+        Ok(())
+    }
+
+    // The mappings sorted by bkpt number
+    pub fn mappings(&self) -> Vec<(u8, &BkptAction)> {
+        let mut mappings: Vec<(u8, &BkptAction)> = self
+            .actions
+            .iter()
+            .map(|(nr, action)| (*nr, action))
+            .collect();
+        mappings.sort_by_key(|(nr, _)| *nr);
+        mappings
+    }
+}
+
+// Parse `<bkpt number>=<action>`, e.g. `7=checkpoint:isr` or `1=ignore`
+pub fn parse_bkpt_mapping(s: &str) -> Result<(u8, BkptAction)> {
+    let (nr, action) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <bkpt number>=<action>, got {:?}", s))?;
+    let nr = nr
+        .trim()
+        .parse::<u8>()
+        .map_err(|_| anyhow!("Invalid bkpt number {:?}, expected 0..255", nr.trim()))?;
+    Ok((nr, action.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bkpt_mapping() {
+        assert_eq!(
+            parse_bkpt_mapping("7=checkpoint:isr").unwrap(),
+            (7, BkptAction::Checkpoint("isr".to_owned()))
+        );
+        assert_eq!(
+            parse_bkpt_mapping(" 8 = checkpoint idle loop").unwrap(),
+            (8, BkptAction::Checkpoint("idle loop".to_owned()))
+        );
+        assert_eq!(
+            parse_bkpt_mapping("1=ignore").unwrap(),
+            (1, BkptAction::Ignore)
+        );
+        assert_eq!(
+            parse_bkpt_mapping("2=region-start").unwrap(),
+            (2, BkptAction::RegionStart)
+        );
+    }
+
+    #[test]
+    fn invalid_bkpt_mapping() {
+        assert!(parse_bkpt_mapping("7").is_err());
+        assert!(parse_bkpt_mapping("256=stop").is_err());
+        assert!(parse_bkpt_mapping("x=stop").is_err());
+        assert!(parse_bkpt_mapping("7=checkpoint").is_err());
+        assert!(parse_bkpt_mapping("7=start now").is_err());
+        assert!(parse_bkpt_mapping("7=jump").is_err());
+    }
+
+    #[test]
+    fn action_display_round_trip() {
+        for (_, action) in BkptProtocol::default().mappings() {
+            assert_eq!(&action.to_string().parse::<BkptAction>().unwrap(), action);
+        }
+        let checkpoint = BkptAction::Checkpoint("isr".to_owned());
+        assert_eq!(
+            checkpoint.to_string().parse::<BkptAction>().unwrap(),
+            checkpoint
+        );
+    }
+}
@@ -0,0 +1,1629 @@
+use super::bkpt_handler::BkptRecord;
+use super::bkpt_protocol::BkptAction;
+use super::ktest::Ktest;
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+use log::warn;
+use object::{Object, ObjectSymbol};
+use probe_rs::config::MemoryRegion;
+use probe_rs::{CoreRegisterAddress, MemoryInterface};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Options of a kleecalc run
+#[derive(Debug, Clone)]
+pub struct KleeCalcOptions {
+    // Zero the cycle counter at every start of a measurement
+    pub reset_cycle_counter: bool,
+    // Reset and halt the target before every ktest run so no state leaks between runs
+    pub reset_target: bool,
+    // Restore RAM and core registers from the first bkpt #2 before every ktest run
+    pub snapshot: bool,
+    // Results file of an interrupted run to continue
+    pub resume: Option<PathBuf>,
+    // .cycles file to write the results to, the next unused one in ktests_results if None
+    pub output: Option<PathBuf>,
+    // Run the ktests KLEE predicts an error for and check that the target faults
+    pub verify_errors: bool,
+    // A ktest that doesn't reach bkpt #1 within the timeout or cycle budget is a hang
+    pub timeout: Option<Duration>,
+    pub cycle_budget: Option<u64>,
+    // Measure the breakpoint overhead before starting and subtract it from all measurements
+    pub calibrate: bool,
+    // Number of times each ktest is injected and measured
+    pub repeat: usize,
+    // Only run ktests with a file name matching one of these globs, all if empty
+    pub include: Vec<String>,
+    // Don't run ktests with a file name matching any of these globs
+    pub exclude: Vec<String>,
+    // First and last ktest file name to run, inclusive
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Paint the unused stack at every start and find how deep it grew at the end
+    pub stack_usage: bool,
+    // File with the bkpt number to action mapping, and mappings that override it
+    pub bkpt_config: Option<PathBuf>,
+    pub bkpt_actions: Vec<(u8, BkptAction)>,
+}
+
+impl Default for KleeCalcOptions {
+    fn default() -> KleeCalcOptions {
+        KleeCalcOptions {
+            reset_cycle_counter: false,
+            reset_target: false,
+            snapshot: false,
+            resume: None,
+            output: None,
+            verify_errors: false,
+            timeout: None,
+            cycle_budget: None,
+            calibrate: false,
+            repeat: 1,
+            include: vec![],
+            exclude: vec![],
+            from: None,
+            to: None,
+            stack_usage: false,
+            bkpt_config: None,
+            bkpt_actions: vec![],
+        }
+    }
+}
+
+// A single symbolic object from a .ktest file
+#[derive(Debug, Clone)]
+pub struct KtestObject {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+// Read all symbolic objects stored in a .ktest file
+pub fn read_ktest_objects(path: &Path) -> Result<Vec<KtestObject>> {
+    Ok(Ktest::read(path)?.objects)
+}
+
+// List the .ktest files to run, sorted by file name
+pub fn list_ktests(dir: &Path, options: &KleeCalcOptions) -> Result<Vec<PathBuf>> {
+    let include = compile_globs(&options.include)?;
+    let exclude = compile_globs(&options.exclude)?;
+
+    let mut ktests = vec![];
+    for entry in fs::read_dir(dir).context("Workdirectory does'nt contain ktests folder")? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |e| e != "ktest") {
+            continue;
+        }
+        let name = file_name(&path);
+        if !include.is_empty() && !include.iter().any(|p| p.matches(&name)) {
+            continue;
+        }
+        if exclude.iter().any(|p| p.matches(&name)) {
+            continue;
+        }
+        if options.from.as_ref().map_or(false, |from| name < *from) {
+            continue;
+        }
+        if options.to.as_ref().map_or(false, |to| name > *to) {
+            continue;
+        }
+        ktests.push(path);
+    }
+    ktests.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(ktests)
+}
+
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn compile_globs(globs: &[String]) -> Result<Vec<Pattern>> {
+    globs
+        .iter()
+        .map(|glob| Pattern::new(glob).with_context(|| format!("Invalid glob {:?}", glob)))
+        .collect()
+}
+
+// The .err files of a directory by file name, read once for all ktests of a run
+pub fn list_err_files(dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut err_files = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = file_name(&path);
+        if name.ends_with(".err") {
+            err_files.insert(name, path);
+        }
+    }
+    Ok(err_files)
+}
+
+// KLEE stores the error of a ktest next to it, e.g. test000001.ptr.err for test000001.ktest
+pub fn find_err_file(err_files: &BTreeMap<String, PathBuf>, ktest_path: &Path) -> Option<PathBuf> {
+    let stem = format!("{}.", ktest_path.file_stem()?.to_string_lossy());
+    // the names starting with the stem follow it in sorted order
+    let (name, path) = err_files.range(stem.clone()..).next()?;
+    match name.starts_with(&stem) {
+        true => Some(path.clone()),
+        false => None,
+    }
+}
+
+// The error KLEE predicts for a ktest, read from its .err file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PredictedError {
+    pub kind: String,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+}
+
+impl PredictedError {
+    pub fn location(&self) -> String {
+        format_location(self.file.as_deref(), self.line)
+    }
+
+    // KLEE reports absolute paths, the DWARF paths may be relative to another directory
+    pub fn matches(&self, file: &str, line: u64) -> bool {
+        match &self.file {
+            Some(predicted) => {
+                let (predicted, observed) = (Path::new(predicted), Path::new(file));
+                self.line == Some(line)
+                    && (predicted.ends_with(observed) || observed.ends_with(predicted))
+            }
+            None => false,
+        }
+    }
+}
+
+// Parse the "Error:", "File:" and "Line:" lines of a KLEE .err file
+pub fn read_err_file(path: &Path) -> Result<PredictedError> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut predicted = PredictedError {
+        kind: "unknown".to_owned(),
+        file: None,
+        line: None,
+    };
+    for line in text.lines() {
+        if let Some(kind) = line.strip_prefix("Error:") {
+            predicted.kind = kind.trim().to_owned();
+        } else if let Some(file) = line.strip_prefix("File:") {
+            predicted.file = Some(file.trim().to_owned());
+        } else if let Some(nr) = line.strip_prefix("Line:") {
+            predicted.line = nr.trim().parse().ok();
+        }
+    }
+    Ok(predicted)
+}
+
+pub fn format_location(file: Option<&str>, line: Option<u64>) -> String {
+    match (file, line) {
+        (Some(file), Some(line)) => format!("{}:{}", file, line),
+        (Some(file), None) => file.to_owned(),
+        _ => "unknown location".to_owned(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    Panic,
+    HardFault,
+    // Another fault exception, by exception number
+    Exception(u32),
+}
+
+impl std::fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FaultKind::Panic => write!(f, "panic"),
+            FaultKind::HardFault => write!(f, "hard fault"),
+            FaultKind::Exception(4) => write!(f, "MemManage fault"),
+            FaultKind::Exception(5) => write!(f, "BusFault"),
+            FaultKind::Exception(6) => write!(f, "UsageFault"),
+            FaultKind::Exception(nr) => write!(f, "exception {}", nr),
+        }
+    }
+}
+
+// Configurable Fault Status, HardFault Status, MemManage and BusFault Address Registers
+const CFSR: u32 = 0xe000ed28;
+const HFSR: u32 = 0xe000ed2c;
+const MMFAR: u32 = 0xe000ed34;
+const BFAR: u32 = 0xe000ed38;
+
+// Debug core register numbers
+const LR_REGISTER: u16 = 14;
+const XPSR_REGISTER: u16 = 16;
+const MSP_REGISTER: u16 = 17;
+const PSP_REGISTER: u16 = 18;
+
+// Fault registers and stacked exception frame of a core halted in a fault handler
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExceptionState {
+    pub exception: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    pub stacked_pc: Option<u32>,
+    pub stacked_lr: Option<u32>,
+}
+
+impl ExceptionState {
+    // Read on the first instruction of the handler, before it changes the stack
+    pub fn read(core: &mut probe_rs::Core) -> Result<ExceptionState> {
+        let exception = exception_number(core)?;
+        let exc_return = core.read_core_reg(LR_REGISTER)?;
+        let mut state = ExceptionState {
+            exception,
+            cfsr: core.read_word_32(CFSR)?,
+            hfsr: core.read_word_32(HFSR)?,
+            mmfar: core.read_word_32(MMFAR)?,
+            bfar: core.read_word_32(BFAR)?,
+            stacked_pc: None,
+            stacked_lr: None,
+        };
+
+        // The frame is r0-r3, r12, lr, pc and xpsr, on the process stack if bit 2 is set
+        if exc_return & 0xf000_0000 == 0xf000_0000 {
+            let sp = match exc_return & (1 << 2) {
+                0 => core.read_core_reg(MSP_REGISTER)?,
+                _ => core.read_core_reg(PSP_REGISTER)?,
+            };
+            let mut frame = [0u32; 8];
+            match core.read_32(sp, &mut frame) {
+                Ok(()) => {
+                    state.stacked_lr = Some(frame[5]);
+                    state.stacked_pc = Some(frame[6]);
+                }
+                Err(err) => warn!("Failed to read the exception frame at {:#010x}: {}", sp, err),
+            };
+        }
+        Ok(state)
+    }
+
+    pub fn fault_kind(&self) -> FaultKind {
+        match self.exception {
+            3 => FaultKind::HardFault,
+            nr => FaultKind::Exception(nr),
+        }
+    }
+}
+
+// The active exception from the IPSR bits of xPSR, 0 in thread mode
+pub fn exception_number(core: &mut probe_rs::Core) -> Result<u32, probe_rs::Error> {
+    Ok(core.read_core_reg(XPSR_REGISTER)? & 0x1ff)
+}
+
+impl std::fmt::Display for ExceptionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "CFSR: {:#010x}, HFSR: {:#010x}, MMFAR: {:#010x}, BFAR: {:#010x}, stacked PC: {}, stacked LR: {}",
+            self.cfsr,
+            self.hfsr,
+            self.mmfar,
+            self.bfar,
+            format_pc(self.stacked_pc),
+            format_pc(self.stacked_lr)
+        )
+    }
+}
+
+// What happened on the target for a ktest KLEE predicted an error for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorVerification {
+    pub predicted: PredictedError,
+    pub fault: Option<FaultKind>,
+    pub location: Option<String>,
+    pub location_matches: bool,
+}
+
+impl ErrorVerification {
+    pub fn new(predicted: PredictedError) -> ErrorVerification {
+        ErrorVerification {
+            predicted,
+            fault: None,
+            location: None,
+            location_matches: false,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorVerification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let predicted = format!(
+            "KLEE predicted {} at {}",
+            self.predicted.kind,
+            self.predicted.location()
+        );
+        match (self.fault, self.location_matches) {
+            (Some(fault), true) => write!(f, "{} ({}, location matches)", fault, predicted),
+            (Some(fault), false) => write!(
+                f,
+                "{} at {} ({})",
+                fault,
+                self.location.as_deref().unwrap_or("unknown location"),
+                predicted
+            ),
+            (None, _) => write!(f, "no fault ({})", predicted),
+        }
+    }
+}
+
+// Address of each of the named symbols in the ELF file, thumb bit cleared
+pub fn find_symbols(elf: &Path, names: &[&str]) -> Result<Vec<(String, u32)>> {
+    let data = fs::read(elf).with_context(|| format!("Failed to read {}", elf.display()))?;
+    let file = object::File::parse(&*data)?;
+    Ok(file
+        .symbols()
+        .filter_map(|symbol| {
+            let name = symbol.name().ok()?;
+            match names.contains(&name) {
+                true => Some((name.to_owned(), symbol.address() as u32 & !1)),
+                false => None,
+            }
+        })
+        .collect())
+}
+
+// Word the unused stack is filled with to find how deep the stack grows
+const STACK_PAINT: u32 = 0xdead_beef;
+
+// Symbol of the lowest address of the stack, cortex-m-rt places it above the heap
+const STACK_END_SYMBOL: &str = "_stack_end";
+
+// Symbols of the end of the statics, the stack must not grow below them
+const STATICS_END_SYMBOLS: [&str; 2] = ["__euninit", "__ebss"];
+
+// Defined when the firmware has a global allocator, its heap starts at the end of the statics
+const ALLOCATOR_SYMBOL: &str = "__rust_alloc";
+
+// The lowest address the stack may grow to and the initial stack pointer, from the
+// cortex-m-rt linker symbols
+#[derive(Debug, Clone, Copy)]
+pub struct StackBounds {
+    pub limit: u32,
+    pub top: Option<u32>,
+}
+
+impl StackBounds {
+    pub fn find(elf: &Path) -> Result<StackBounds> {
+        let mut names = vec!["_stack_start", STACK_END_SYMBOL, ALLOCATOR_SYMBOL];
+        names.extend_from_slice(&STATICS_END_SYMBOLS);
+        let symbols = find_symbols(elf, &names)?;
+        let address = |name: &str| {
+            symbols
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, address)| *address)
+        };
+        // Painting down to the end of the statics would overwrite the heap
+        let limit = match address(STACK_END_SYMBOL) {
+            Some(limit) => limit,
+            None if address(ALLOCATOR_SYMBOL).is_some() => {
+                return Err(anyhow!(
+                    "The firmware has a heap below the stack but no {} symbol marking where the stack ends",
+                    STACK_END_SYMBOL
+                ))
+            }
+            None => STATICS_END_SYMBOLS
+                .iter()
+                .find_map(|name| address(name))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "No stack limit in the elf, expected one of the symbols {}, {}",
+                        STACK_END_SYMBOL,
+                        STATICS_END_SYMBOLS.join(", ")
+                    )
+                })?,
+        };
+        Ok(StackBounds {
+            limit: (limit + 3) & !3,
+            top: address("_stack_start"),
+        })
+    }
+}
+
+// Fill the stack from the limit up to the stack pointer with STACK_PAINT, returns the painted range
+pub fn paint_stack(core: &mut probe_rs::Core, limit: u32, sp: u32) -> Result<Range<u32>> {
+    let painted = limit..(sp & !3);
+    if painted.is_empty() {
+        return Err(anyhow!(
+            "The stack pointer {:#010x} is below the stack limit {:#010x}",
+            sp,
+            limit
+        ));
+    }
+    let words = vec![STACK_PAINT; ((painted.end - painted.start) / 4) as usize];
+    core.write_32(painted.start, &words)?;
+    Ok(painted)
+}
+
+// The lowest address of the painted range the stack has written to, None if it didn't grow into it
+pub fn deepest_stack_write(core: &mut probe_rs::Core, painted: &Range<u32>) -> Result<Option<u32>> {
+    let mut words = vec![0u32; ((painted.end - painted.start) / 4) as usize];
+    core.read_32(painted.start, &mut words)?;
+    Ok(words
+        .iter()
+        .position(|word| *word != STACK_PAINT)
+        .map(|i| painted.start + 4 * i as u32))
+}
+
+// Write a ktest object byte by byte to the address of the symbolic variable.
+// `size` is the size given to `klee_make_symbolic` and must match the object size.
+pub fn write_ktest_object(
+    core: &mut probe_rs::Core,
+    object: &KtestObject,
+    address: u32,
+    size: usize,
+) -> Result<()> {
+    if object.bytes.len() != size {
+        return Err(anyhow!(
+            "Size mismatch for ktest object {:?}: ktest holds {} bytes but klee_make_symbolic was called with {} bytes at {:#010x}",
+            object.name,
+            object.bytes.len(),
+            size,
+            address
+        ));
+    }
+    core.write_8(address, &object.bytes)?;
+    Ok(())
+}
+
+// Read a null terminated string from target memory, e.g. the name given to `klee_make_symbolic`
+pub fn read_c_string(core: &mut probe_rs::Core, address: u32) -> Result<String> {
+    const CHUNK_SIZE: usize = 32;
+    const MAX_LENGTH: usize = 256;
+
+    let mut bytes = vec![];
+    while bytes.len() < MAX_LENGTH {
+        let mut chunk = [0u8; CHUNK_SIZE];
+        // a garbage pointer near the end of the address space must not wrap around
+        let chunk_address = address
+            .checked_add(bytes.len() as u32)
+            .filter(|a| a.checked_add(CHUNK_SIZE as u32).is_some())
+            .ok_or_else(|| anyhow!("Invalid string address {:#010x}", address))?;
+        core.read_8(chunk_address, &mut chunk)?;
+        match chunk.iter().position(|b| *b == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            None => bytes.extend_from_slice(&chunk),
+        };
+    }
+    Err(anyhow!(
+        "String at {:#010x} is not null terminated within {} bytes",
+        address,
+        MAX_LENGTH
+    ))
+}
+
+// Describe klee calls without a matching ktest object and ktest objects that were never used
+pub fn format_symbolic_notes(unknown_symbolics: &[String], unused_objects: &[String]) -> String {
+    let mut notes = String::new();
+    if !unknown_symbolics.is_empty() {
+        notes += &format!(", unknown symbolic variables: {:?}", unknown_symbolics);
+    }
+    if !unused_objects.is_empty() {
+        notes += &format!(", unused ktest objects: {:?}", unused_objects);
+    }
+    notes
+}
+
+// RAM and core registers of a halted target
+pub struct TargetSnapshot {
+    ram: Vec<(u32, Vec<u8>)>,
+    registers: Vec<(CoreRegisterAddress, u32)>,
+}
+
+impl TargetSnapshot {
+    pub fn take(core: &mut probe_rs::Core, ram: &[Range<u32>]) -> Result<TargetSnapshot> {
+        let mut snapshot = TargetSnapshot {
+            ram: vec![],
+            registers: vec![],
+        };
+        for range in ram {
+            let mut bytes = vec![0u8; (range.end - range.start) as usize];
+            core.read_8(range.start, &mut bytes)
+                .with_context(|| format!("Failed to read RAM at {:#010x}", range.start))?;
+            snapshot.ram.push((range.start, bytes));
+        }
+        for register in core.registers().registers() {
+            let value = core.read_core_reg(register)?;
+            snapshot.registers.push((register.into(), value));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn restore(&self, core: &mut probe_rs::Core) -> Result<()> {
+        for (address, bytes) in &self.ram {
+            core.write_8(*address, bytes)
+                .with_context(|| format!("Failed to restore RAM at {:#010x}", address))?;
+        }
+        for (register, value) in &self.registers {
+            core.write_core_reg(*register, *value)?;
+        }
+        Ok(())
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram.iter().map(|(_, bytes)| bytes.len()).sum()
+    }
+}
+
+// The RAM regions of the target memory map
+pub fn ram_ranges(memory_map: &[MemoryRegion]) -> Vec<Range<u32>> {
+    memory_map
+        .iter()
+        .filter_map(|region| match region {
+            MemoryRegion::Ram(ram) => Some(ram.range.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Information about the kleecalc run stored at the top of the result files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultHeader {
+    pub elf_file: String,
+    pub chip: String,
+    pub probe: usize,
+    pub timestamp: String,
+    // Cycles of halting on and resuming from a breakpoint, subtracted from all cycle counts
+    #[serde(default)]
+    pub overhead_cycles: Option<u64>,
+}
+
+// A ktest object as it was written to the target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InjectedObject {
+    pub name: String,
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    // Value decoded with the DWARF type of the variable the bytes were written to
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KtestStatus {
+    Measured,
+    Skipped,
+    Error,
+    Faulted,
+    Hang,
+}
+
+impl KtestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KtestStatus::Measured => "measured",
+            KtestStatus::Skipped => "skipped",
+            KtestStatus::Error => "error",
+            KtestStatus::Faulted => "faulted",
+            KtestStatus::Hang => "hang",
+        }
+    }
+}
+
+// Longest time between two samples of the cycle counter that can't hide a wrap.
+// The 32-bit counter wraps after about 4.3 s at 1 GHz.
+const MAX_SAMPLE_GAP: Duration = Duration::from_secs(4);
+
+// A point in time of the extended cycle counter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CycleStamp {
+    pub cycles: u64,
+    gaps: u64,
+    halts: u64,
+}
+
+// Extends the 32-bit DWT cycle counter to 64 bits.
+// The counter is sampled on every halt and while the core runs, a sample smaller than the
+// previous one means the counter has wrapped. If too much time passes between two samples
+// the counter may have wrapped more than once, which is counted as a gap.
+#[derive(Default)]
+pub struct CycleCounter {
+    wraps: u64,
+    gaps: u64,
+    halts: u64,
+    last: Option<(u32, Instant)>,
+}
+
+impl CycleCounter {
+    pub fn new() -> CycleCounter {
+        CycleCounter::default()
+    }
+
+    pub fn sample(&mut self, cyccnt: u32) -> CycleStamp {
+        if let Some((last_cyccnt, last_time)) = self.last {
+            if last_time.elapsed() > MAX_SAMPLE_GAP {
+                self.gaps += 1;
+            }
+            if cyccnt < last_cyccnt {
+                self.wraps += 1;
+            }
+        }
+        self.last = Some((cyccnt, Instant::now()));
+
+        CycleStamp {
+            cycles: (self.wraps << 32) + cyccnt as u64,
+            gaps: self.gaps,
+            halts: self.halts,
+        }
+    }
+
+    // Sample the counter when the core has halted on a breakpoint
+    pub fn sample_halted(&mut self, cyccnt: u32) -> CycleStamp {
+        self.halts += 1;
+        self.sample(cyccnt)
+    }
+
+    // The counter doesn't count while the core is halted, so the time until the next sample
+    // is only taken from when the core is resumed
+    pub fn resumed(&mut self) {
+        if let Some((last_cyccnt, _)) = self.last {
+            self.last = Some((last_cyccnt, Instant::now()));
+        }
+    }
+
+    // Forget the previous sample, e.g. when the counter has been reset
+    pub fn restart(&mut self) {
+        self.last = None;
+    }
+}
+
+// Time the core has been running since a restart, used for the ktest timeout.
+// Only the time between two checks that found the core running is counted, the time before
+// a halt is noticed and the time the core stays halted are not.
+#[derive(Debug, Default)]
+pub struct RunningTime {
+    elapsed: Duration,
+    last_running: Option<Instant>,
+}
+
+impl RunningTime {
+    pub fn restart(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.last_running = None;
+    }
+
+    pub fn resumed(&mut self) {
+        self.last_running = Some(Instant::now());
+    }
+
+    // The core was found running
+    pub fn running(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_running {
+            self.elapsed += now - last;
+        }
+        self.last_running = Some(now);
+    }
+
+    // The core was found halted
+    pub fn halted(&mut self) {
+        self.last_running = None;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+// The cycles between two samples of the cycle counter
+pub struct CycleDelta {
+    pub cycles: u64,
+    pub halts: u64,
+    pub may_have_lost_wrap: bool,
+}
+
+impl CycleDelta {
+    // Every halt on a breakpoint and the following resume adds `overhead` cycles
+    pub fn without_overhead(&self, overhead: u64) -> u64 {
+        self.cycles.saturating_sub(self.halts * overhead)
+    }
+}
+
+impl CycleStamp {
+    pub fn since(&self, start: &CycleStamp) -> CycleDelta {
+        CycleDelta {
+            cycles: self.cycles.saturating_sub(start.cycles),
+            halts: self.halts - start.halts,
+            may_have_lost_wrap: self.gaps != start.gaps,
+        }
+    }
+}
+
+// Cycles measured for a labelled region, repeated regions are added together
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionCycles {
+    pub label: String,
+    pub cycles: u64,
+    #[serde(default)]
+    pub raw_cycles: u64,
+    #[serde(default)]
+    pub may_have_lost_wrap: bool,
+}
+
+// Cycles from the start of the measurement to a named checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointCycles {
+    pub name: String,
+    pub cycles: u64,
+}
+
+pub fn add_region_cycles(
+    regions: &mut Vec<RegionCycles>,
+    label: String,
+    delta: &CycleDelta,
+    overhead: u64,
+) {
+    let cycles = delta.without_overhead(overhead);
+    match regions.iter_mut().find(|r| r.label == label) {
+        Some(region) => {
+            region.cycles += cycles;
+            region.raw_cycles += delta.cycles;
+            region.may_have_lost_wrap |= delta.may_have_lost_wrap;
+        }
+        None => regions.push(RegionCycles {
+            label,
+            cycles,
+            raw_cycles: delta.cycles,
+            may_have_lost_wrap: delta.may_have_lost_wrap,
+        }),
+    };
+}
+
+// The result of running one ktest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KtestResult {
+    pub ktest: String,
+    pub status: KtestStatus,
+    pub cycles: Option<u64>,
+    #[serde(default)]
+    pub raw_cycles: Option<u64>,
+    #[serde(default)]
+    pub may_have_lost_wrap: bool,
+    #[serde(default)]
+    pub regions: Vec<RegionCycles>,
+    // Cycles of every run of the ktest, `cycles` is the largest one
+    #[serde(default)]
+    pub samples: Vec<u64>,
+    #[serde(default)]
+    pub sample_stats: Option<SampleStats>,
+    pub objects: Vec<InjectedObject>,
+    pub start_pc: Option<u32>,
+    pub end_pc: Option<u32>,
+    pub unknown_symbolics: Vec<String>,
+    pub unused_objects: Vec<String>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub over_budget: bool,
+    #[serde(default)]
+    pub verification: Option<ErrorVerification>,
+    #[serde(default)]
+    pub exception: Option<ExceptionState>,
+    // Innermost frame first, recorded when the ktest didn't reach bkpt #1
+    #[serde(default)]
+    pub stack: Vec<String>,
+    #[serde(default)]
+    pub checkpoints: Vec<CheckpointCycles>,
+    // Deepest the stack grew in bytes, from the top of the stack or the stack pointer at the start
+    #[serde(default)]
+    pub stack_usage: Option<u64>,
+    // The stack grew down to its limit and may have overflowed
+    #[serde(default)]
+    pub stack_overflow: bool,
+    // Values recorded by the registered bkpt handlers during the ktest
+    #[serde(default)]
+    pub records: Vec<BkptRecord>,
+}
+
+impl KtestResult {
+    pub fn new(ktest: String, start_pc: Option<u32>) -> KtestResult {
+        KtestResult {
+            ktest,
+            status: KtestStatus::Measured,
+            cycles: None,
+            raw_cycles: None,
+            may_have_lost_wrap: false,
+            regions: vec![],
+            samples: vec![],
+            sample_stats: None,
+            objects: vec![],
+            start_pc,
+            end_pc: None,
+            unknown_symbolics: vec![],
+            unused_objects: vec![],
+            error: None,
+            over_budget: false,
+            verification: None,
+            exception: None,
+            stack: vec![],
+            checkpoints: vec![],
+            stack_usage: None,
+            stack_overflow: false,
+            records: vec![],
+        }
+    }
+
+    // Add the measurement of one run, the run with the most cycles is reported
+    pub fn add_sample(
+        &mut self,
+        cycles: u64,
+        raw_cycles: u64,
+        regions: Vec<RegionCycles>,
+        may_have_lost_wrap: bool,
+    ) {
+        self.samples.push(cycles);
+        self.sample_stats = Some(SampleStats::new(&self.samples));
+        if self.cycles.map_or(true, |max| cycles > max) {
+            self.cycles = Some(cycles);
+            self.raw_cycles = Some(raw_cycles);
+        }
+        self.may_have_lost_wrap |=
+            may_have_lost_wrap || regions.iter().any(|r| r.may_have_lost_wrap);
+        for region in regions {
+            match self.regions.iter_mut().find(|r| r.label == region.label) {
+                Some(max) => {
+                    if region.cycles > max.cycles {
+                        *max = region;
+                    }
+                }
+                None => self.regions.push(region),
+            };
+        }
+    }
+
+    // Keep the deepest stack usage over the runs of the ktest
+    pub fn add_stack_usage(&mut self, usage: u64, overflow: bool) {
+        self.stack_usage = Some(self.stack_usage.map_or(usage, |max| max.max(usage)));
+        self.stack_overflow |= overflow;
+    }
+
+    // Keep the largest cycles of every checkpoint over the runs of the ktest
+    pub fn add_checkpoints(&mut self, checkpoints: Vec<CheckpointCycles>) {
+        for checkpoint in checkpoints {
+            match self.checkpoints.iter_mut().find(|c| c.name == checkpoint.name) {
+                Some(max) => max.cycles = max.cycles.max(checkpoint.cycles),
+                None => self.checkpoints.push(checkpoint),
+            };
+        }
+    }
+
+    // Mark the ktest as failed, the first error is kept
+    pub fn set_error(&mut self, message: String) {
+        if self.error.is_none() {
+            self.error = Some(message);
+        }
+        self.status = KtestStatus::Error;
+    }
+}
+
+// Spread of the cycles over the runs of a ktest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+impl SampleStats {
+    pub fn new(samples: &[u64]) -> SampleStats {
+        let count = samples.len().max(1) as f64;
+        let mean = samples.iter().map(|s| *s as f64).sum::<f64>() / count;
+        let variance = samples
+            .iter()
+            .map(|s| (*s as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        SampleStats {
+            min: samples.iter().copied().min().unwrap_or(0),
+            max: samples.iter().copied().max().unwrap_or(0),
+            mean,
+            stddev: variance.sqrt(),
+        }
+    }
+}
+
+// Content of the .json result file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultSet {
+    pub header: ResultHeader,
+    pub results: Vec<KtestResult>,
+    #[serde(default)]
+    pub summary: Option<CycleSummary>,
+    #[serde(default)]
+    pub region_summaries: Vec<CycleSummary>,
+}
+
+impl ResultSet {
+    // Region labels in the order they were first measured
+    pub fn region_labels(&self) -> Vec<String> {
+        let mut labels: Vec<String> = vec![];
+        for result in &self.results {
+            for region in &result.regions {
+                if !labels.contains(&region.label) {
+                    labels.push(region.label.clone());
+                }
+            }
+        }
+        labels
+    }
+}
+
+// Label of the region between bkpt #2 and bkpt #1
+pub const TOTAL_LABEL: &str = "total";
+
+// Statistics over the cycle counts of all measured ktests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CycleSummary {
+    #[serde(default = "total_label")]
+    pub label: String,
+    pub count: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub median: f64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub worst_ktest: String,
+    pub worst_objects: Vec<InjectedObject>,
+}
+
+fn total_label() -> String {
+    TOTAL_LABEL.to_owned()
+}
+
+impl CycleSummary {
+    // Summarize the whole measurement of all ktests measured without errors, None if there are none
+    pub fn new(results: &[KtestResult]) -> Option<CycleSummary> {
+        let measured = results
+            .iter()
+            .filter(|r| r.status == KtestStatus::Measured)
+            .filter_map(|r| r.cycles.map(|cycles| (r, cycles)))
+            .collect();
+        CycleSummary::from_measurements(TOTAL_LABEL.to_owned(), measured)
+    }
+
+    // Summarize a labelled region of all ktests measured without errors
+    pub fn new_region(results: &[KtestResult], label: &str) -> Option<CycleSummary> {
+        let measured = results
+            .iter()
+            .filter(|r| r.status == KtestStatus::Measured)
+            .filter_map(|r| {
+                r.regions
+                    .iter()
+                    .find(|region| region.label == label)
+                    .map(|region| (r, region.cycles))
+            })
+            .collect();
+        CycleSummary::from_measurements(label.to_owned(), measured)
+    }
+
+    fn from_measurements(
+        label: String,
+        measured: Vec<(&KtestResult, u64)>,
+    ) -> Option<CycleSummary> {
+        let (worst, _) = *measured.iter().max_by_key(|(_, cycles)| *cycles)?;
+
+        let mut cycles: Vec<u64> = measured.iter().map(|(_, cycles)| *cycles).collect();
+        cycles.sort_unstable();
+        let count = cycles.len();
+        let median = match count % 2 {
+            0 => (cycles[count / 2 - 1] as f64 + cycles[count / 2] as f64) / 2.0,
+            _ => cycles[count / 2] as f64,
+        };
+
+        Some(CycleSummary {
+            label,
+            count,
+            min: cycles[0],
+            max: cycles[count - 1],
+            mean: cycles.iter().map(|c| *c as f64).sum::<f64>() / count as f64,
+            median,
+            p90: percentile(&cycles, 90),
+            p95: percentile(&cycles, 95),
+            p99: percentile(&cycles, 99),
+            worst_ktest: worst.ktest.clone(),
+            worst_objects: worst.objects.clone(),
+        })
+    }
+}
+
+impl std::fmt::Display for CycleSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "WCET summary for {} ({} measured ktests):",
+            self.label, self.count
+        )?;
+        writeln!(
+            f,
+            "\tmin: {}, max: {}, mean: {:.1}, median: {:.1}",
+            self.min, self.max, self.mean, self.median
+        )?;
+        writeln!(
+            f,
+            "\tp90: {}, p95: {}, p99: {}",
+            self.p90, self.p95, self.p99
+        )?;
+        write!(f, "\tworst case: {}, {} cycles", self.worst_ktest, self.max)?;
+        for object in &self.worst_objects {
+            write!(
+                f,
+                "\n\t\t{} at {:#010x}: {}",
+                object.name,
+                object.address,
+                to_hex(&object.bytes)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+// The .cycles, .json and .csv files of a kleecalc run
+pub struct ResultFiles {
+    text_path: PathBuf,
+    json_path: PathBuf,
+    csv_path: PathBuf,
+    set: ResultSet,
+    // Region labels with a column in the csv file
+    csv_labels: Vec<String>,
+    // Results were added since the .json file was written
+    json_outdated: bool,
+}
+
+const CSV_COLUMNS: &str =
+    "ktest,status,cycles,raw_cycles,stack_usage,stack_overflow,may_have_lost_wrap,samples,sample_min,sample_max,sample_stddev,start_pc,end_pc,objects,object_values,unknown_symbolics,unused_objects,error,over_budget,stack,checkpoints,predicted_error,predicted_location,fault,fault_location,location_matches,exception,cfsr,hfsr,mmfar,bfar,stacked_pc,stacked_lr";
+
+impl ResultFiles {
+    // Create the result files, `text_path` is the .cycles file and the others are put next to it
+    pub fn create(
+        text_path: PathBuf,
+        header: ResultHeader,
+        text_header: &str,
+    ) -> Result<ResultFiles> {
+        let mut result_files = ResultFiles {
+            json_path: text_path.with_extension("json"),
+            csv_path: text_path.with_extension("csv"),
+            text_path,
+            set: ResultSet {
+                header,
+                results: vec![],
+                summary: None,
+                region_summaries: vec![],
+            },
+            csv_labels: vec![],
+            json_outdated: false,
+        };
+
+        File::create(&result_files.text_path)?.write_all(text_header.as_bytes())?;
+        result_files.write_csv()?;
+        result_files.write_json()?;
+        Ok(result_files)
+    }
+
+    // Open the result files of an earlier run to add more results to them.
+    // Runs without a .json file only have the measured ktests of the .cycles file.
+    pub fn open(text_path: PathBuf, header: ResultHeader) -> Result<ResultFiles> {
+        let json_path = text_path.with_extension("json");
+        let mut set = match json_path.exists() {
+            true => {
+                let file = File::open(&json_path)?;
+                serde_json::from_reader::<_, ResultSet>(file)
+                    .with_context(|| format!("Failed to parse {}", json_path.display()))?
+            }
+            false => ResultSet {
+                header,
+                results: read_text_results(&text_path)?,
+                summary: None,
+                region_summaries: vec![],
+            },
+        };
+        // the summaries are made again when the run finishes
+        set.summary = None;
+        set.region_summaries = vec![];
+
+        let mut result_files = ResultFiles {
+            json_path,
+            csv_path: text_path.with_extension("csv"),
+            text_path,
+            set,
+            csv_labels: vec![],
+            json_outdated: false,
+        };
+        result_files.write_csv()?;
+        result_files.write_json()?;
+        Ok(result_files)
+    }
+
+    // Whether the ktest already has a result
+    pub fn contains(&self, ktest: &str) -> bool {
+        self.set.results.iter().any(|result| result.ktest == ktest)
+    }
+
+    pub fn text_path(&self) -> &Path {
+        &self.text_path
+    }
+
+    pub fn results(&self) -> &[KtestResult] {
+        &self.set.results
+    }
+
+    // Add a ktest result to the result files. The .cycles and .csv files get a line each, the
+    // .json file is written when the run finishes or the result files are dropped.
+    pub fn write(&mut self, result: KtestResult) -> Result<()> {
+        if let Some(line) = format_result_line(&result) {
+            let mut text = fs::OpenOptions::new().append(true).open(&self.text_path)?;
+            write!(text, "{}", line)?;
+        }
+
+        let new_label = result
+            .regions
+            .iter()
+            .any(|region| !self.csv_labels.contains(&region.label));
+        self.set.results.push(result);
+        self.json_outdated = true;
+        match new_label {
+            // each region label gets its own column, so a new label needs a new header
+            true => self.write_csv(),
+            false => {
+                let mut csv = fs::OpenOptions::new().append(true).open(&self.csv_path)?;
+                if let Some(result) = self.set.results.last() {
+                    writeln!(csv, "{}", format_csv_row(result, &self.csv_labels))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Append the WCET summaries to the result files, the whole measurement comes first
+    pub fn finish(&mut self) -> Result<Vec<CycleSummary>> {
+        self.set.summary = CycleSummary::new(&self.set.results);
+        self.set.region_summaries = self
+            .set
+            .region_labels()
+            .iter()
+            .filter_map(|label| CycleSummary::new_region(&self.set.results, label))
+            .collect();
+
+        let summaries: Vec<CycleSummary> = self
+            .set
+            .summary
+            .iter()
+            .chain(self.set.region_summaries.iter())
+            .cloned()
+            .collect();
+        let mut text = fs::OpenOptions::new().append(true).open(&self.text_path)?;
+        for summary in &summaries {
+            write!(text, "\n\n{}", summary)?;
+        }
+        self.write_json()?;
+        Ok(summaries)
+    }
+
+    // Write the .json file if results were added since it was last written
+    pub fn flush(&mut self) -> Result<()> {
+        match self.json_outdated {
+            true => self.write_json(),
+            false => Ok(()),
+        }
+    }
+
+    fn write_json(&mut self) -> Result<()> {
+        let file = File::create(&self.json_path)?;
+        serde_json::to_writer_pretty(file, &self.set)?;
+        self.json_outdated = false;
+        Ok(())
+    }
+
+    // Write the whole csv file
+    fn write_csv(&mut self) -> Result<()> {
+        // The run header is only in the .json file so the .csv stays plain
+        let mut csv = File::create(&self.csv_path)?;
+        let labels = self.set.region_labels();
+        let mut columns = CSV_COLUMNS.to_owned();
+        for label in &labels {
+            columns += &format!(",{}", csv_field(label));
+        }
+        writeln!(csv, "{}", columns)?;
+
+        for result in &self.set.results {
+            writeln!(csv, "{}", format_csv_row(result, &labels))?;
+        }
+        self.csv_labels = labels;
+        Ok(())
+    }
+}
+
+// An interrupted run still gets a .json file with the results so far
+impl Drop for ResultFiles {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+    }
+}
+
+// Read the measured ktests back from the lines of a .cycles file
+pub fn read_text_results(text_path: &Path) -> Result<Vec<KtestResult>> {
+    let text = fs::read_to_string(text_path)
+        .with_context(|| format!("Failed to read {}", text_path.display()))?;
+    let mut results = vec![];
+    for line in text.lines() {
+        // header and summary lines can also look like "<name>: <n> cycles"
+        let (ktest, rest) = match line.split_once(": ") {
+            Some((ktest, rest)) if ktest.ends_with(".ktest") => (ktest, rest),
+            _ => continue,
+        };
+        let mut words = rest.split_whitespace();
+        let cycles = words.next().and_then(|word| word.parse::<u64>().ok());
+        if let (Some(cycles), Some("cycles")) = (cycles, words.next()) {
+            let mut result = KtestResult::new(ktest.to_owned(), None);
+            result.cycles = Some(cycles);
+            results.push(result);
+        }
+    }
+    Ok(results)
+}
+
+// The line written to the .cycles file, skipped ktests are left out
+fn format_result_line(result: &KtestResult) -> Option<String> {
+    let cycles = match result.cycles {
+        Some(cycles) => cycles,
+        None => return format_unmeasured_line(result),
+    };
+    let mut line = format!("\n{}: {} cycles", result.ktest, cycles);
+    if let Some(raw_cycles) = result.raw_cycles {
+        if raw_cycles != cycles {
+            line += &format!(" ({} raw)", raw_cycles);
+        }
+    }
+    if result.samples.len() > 1 {
+        if let Some(stats) = &result.sample_stats {
+            line += &format!(
+                ", samples: {:?}, min: {}, max: {}, stddev: {:.1}",
+                result.samples, stats.min, stats.max, stats.stddev
+            );
+        }
+    }
+    if result.may_have_lost_wrap {
+        line += ", may have lost a cycle counter wrap";
+    }
+    for region in &result.regions {
+        line += &format!(", {}: {} cycles", region.label, region.cycles);
+    }
+    for checkpoint in &result.checkpoints {
+        line += &format!(", {}: {} cycles", checkpoint.name, checkpoint.cycles);
+    }
+    if let Some(stack_usage) = result.stack_usage {
+        line += &format!(", stack: {} bytes", stack_usage);
+    }
+    if result.stack_overflow {
+        line += ", stack may have overflowed";
+    }
+    for object in &result.objects {
+        line += &format!(", {} = 0x{}", object.name, to_hex(&object.bytes));
+        if let Some(value) = &object.value {
+            line += &format!(" ({})", value);
+        }
+    }
+    if let Some(error) = &result.error {
+        line += &format!(", error: {}", error);
+    }
+    if result.over_budget {
+        line += ", over the cycle budget";
+    }
+    if let Some(verification) = &result.verification {
+        line += &format!(", {}", verification);
+    }
+    if let Some(exception) = &result.exception {
+        line += &format!(", {}", exception);
+    }
+    line += &format_symbolic_notes(&result.unknown_symbolics, &result.unused_objects);
+    Some(line)
+}
+
+// The line of a ktest that didn't reach bkpt #1
+fn format_unmeasured_line(result: &KtestResult) -> Option<String> {
+    let mut line = match (&result.verification, &result.error) {
+        (Some(verification), _) => format!("\n{}: {}", result.ktest, verification),
+        (None, Some(error)) => format!("\n{}: {}, {}", result.ktest, result.status.as_str(), error),
+        (None, None) => return None,
+    };
+    if let Some(exception) = &result.exception {
+        line += &format!(", {}", exception);
+    }
+    Some(line)
+}
+
+fn format_csv_row(result: &KtestResult, labels: &[String]) -> String {
+    let objects: Vec<String> = result
+        .objects
+        .iter()
+        .map(|o| format!("{}={}", o.name, to_hex(&o.bytes)))
+        .collect();
+    let values: Vec<String> = result
+        .objects
+        .iter()
+        .filter_map(|o| Some(format!("{}={}", o.name, o.value.as_ref()?)))
+        .collect();
+    let samples: Vec<String> = result.samples.iter().map(|s| s.to_string()).collect();
+    let checkpoints: Vec<String> = result
+        .checkpoints
+        .iter()
+        .map(|c| format!("{}={}", c.name, c.cycles))
+        .collect();
+    let mut row = vec![
+        csv_field(&result.ktest),
+        result.status.as_str().to_owned(),
+        format_option(&result.cycles),
+        format_option(&result.raw_cycles),
+        format_option(&result.stack_usage),
+        result.stack_overflow.to_string(),
+        result.may_have_lost_wrap.to_string(),
+        samples.join(";"),
+        format_option(&result.sample_stats.as_ref().map(|s| s.min)),
+        format_option(&result.sample_stats.as_ref().map(|s| s.max)),
+        format_option(
+            &result
+                .sample_stats
+                .as_ref()
+                .map(|s| format!("{:.1}", s.stddev)),
+        ),
+        format_pc(result.start_pc),
+        format_pc(result.end_pc),
+        csv_field(&objects.join(";")),
+        csv_field(&values.join(";")),
+        csv_field(&result.unknown_symbolics.join(";")),
+        csv_field(&result.unused_objects.join(";")),
+        csv_field(result.error.as_deref().unwrap_or("")),
+        result.over_budget.to_string(),
+        csv_field(&result.stack.join(";")),
+        csv_field(&checkpoints.join(";")),
+    ];
+    match &result.verification {
+        Some(verification) => row.extend(vec![
+            csv_field(&verification.predicted.kind),
+            csv_field(&verification.predicted.location()),
+            format_option(&verification.fault),
+            csv_field(verification.location.as_deref().unwrap_or("")),
+            verification.location_matches.to_string(),
+        ]),
+        None => row.extend(vec![String::new(); 5]),
+    };
+    match &result.exception {
+        Some(exception) => row.extend(vec![
+            exception.exception.to_string(),
+            format!("{:#010x}", exception.cfsr),
+            format!("{:#010x}", exception.hfsr),
+            format!("{:#010x}", exception.mmfar),
+            format!("{:#010x}", exception.bfar),
+            format_pc(exception.stacked_pc),
+            format_pc(exception.stacked_lr),
+        ]),
+        None => row.extend(vec![String::new(); 7]),
+    };
+    for label in labels {
+        let cycles = result
+            .regions
+            .iter()
+            .find(|r| &r.label == label)
+            .map(|r| r.cycles);
+        row.push(format_option(&cycles));
+    }
+    row.join(",")
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn format_option<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn format_pc(pc: Option<u32>) -> String {
+    pc.map(|pc| format!("{:#010x}", pc)).unwrap_or_default()
+}
+
+// Quote a csv field if it contains a separator or a quote
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentile() {
+        let sorted: Vec<u64> = (1..=10).collect();
+        assert_eq!(percentile(&sorted, 0), 1);
+        assert_eq!(percentile(&sorted, 50), 5);
+        assert_eq!(percentile(&sorted, 90), 9);
+        assert_eq!(percentile(&sorted, 95), 10);
+        assert_eq!(percentile(&sorted, 99), 10);
+        assert_eq!(percentile(&[7], 90), 7);
+    }
+
+    #[test]
+    fn cycle_counter_wraps() {
+        let mut counter = CycleCounter::new();
+        let start = counter.sample_halted(u32::MAX - 9);
+        assert_eq!(counter.sample(u32::MAX).cycles, u32::MAX as u64);
+        let end = counter.sample_halted(20);
+        assert_eq!(end.cycles, (1 << 32) + 20);
+
+        let delta = end.since(&start);
+        assert_eq!(delta.cycles, 30);
+        assert_eq!(delta.halts, 1);
+        assert!(!delta.may_have_lost_wrap);
+        assert_eq!(delta.without_overhead(5), 25);
+    }
+
+    #[test]
+    fn cycle_counter_restart() {
+        let mut counter = CycleCounter::new();
+        counter.sample(1000);
+        counter.restart();
+        // A reset counter is smaller without having wrapped
+        assert_eq!(counter.sample(10).cycles, 10);
+        counter.resumed();
+        assert_eq!(counter.sample(5).cycles, (1 << 32) + 5);
+    }
+
+    #[test]
+    fn err_file() {
+        let dir = std::env::temp_dir().join(format!("kleecalc-err-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ktest = dir.join("test000001.ktest");
+        let err = dir.join("test000001.ptr.err");
+        fs::write(&ktest, b"").unwrap();
+        fs::write(
+            &err,
+            "Error: memory error: out of bound pointer\nFile: /src/main.rs\nLine: 42\nassembly.ll line: 100\n",
+        )
+        .unwrap();
+
+        let found = find_err_file(&list_err_files(&dir).unwrap(), &ktest);
+        let predicted = read_err_file(&err).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(found, Some(err));
+        assert_eq!(predicted.kind, "memory error: out of bound pointer");
+        assert_eq!(predicted.file.as_deref(), Some("/src/main.rs"));
+        assert_eq!(predicted.line, Some(42));
+        assert!(predicted.matches("main.rs", 42));
+        assert!(!predicted.matches("main.rs", 41));
+    }
+
+    #[test]
+    fn ktest_selection() {
+        let dir = std::env::temp_dir().join(format!("kleecalc-list-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // test000003 and test000004 are missing and one ktest was renamed
+        let files = [
+            "test000005.ktest",
+            "test000001.ktest",
+            "overflow.ktest",
+            "test000002.ktest",
+            "test000002.ptr.err",
+            "info",
+        ];
+        for file in files {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        let list = |options: KleeCalcOptions| -> Vec<String> {
+            list_ktests(&dir, &options)
+                .unwrap()
+                .iter()
+                .map(|path| file_name(path))
+                .collect()
+        };
+
+        let all = list(KleeCalcOptions::default());
+        let include = list(KleeCalcOptions {
+            include: vec!["test*".to_owned()],
+            exclude: vec!["*2.ktest".to_owned()],
+            ..KleeCalcOptions::default()
+        });
+        let range = list(KleeCalcOptions {
+            from: Some("test000002.ktest".to_owned()),
+            to: Some("test000005.ktest".to_owned()),
+            ..KleeCalcOptions::default()
+        });
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            all,
+            vec![
+                "overflow.ktest",
+                "test000001.ktest",
+                "test000002.ktest",
+                "test000005.ktest"
+            ]
+        );
+        assert_eq!(include, vec!["test000001.ktest", "test000005.ktest"]);
+        assert_eq!(range, vec!["test000002.ktest", "test000005.ktest"]);
+    }
+
+    #[test]
+    fn text_results() {
+        let dir = std::env::temp_dir().join(format!("kleecalc-text-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let header = ResultHeader {
+            elf_file: "app".to_owned(),
+            chip: "nrf52".to_owned(),
+            probe: 0,
+            timestamp: "now".to_owned(),
+            overhead_cycles: Some(12),
+        };
+        let mut files = ResultFiles::create(
+            dir.join("1.cycles"),
+            header,
+            "/work\n/work/app\nOverhead: 12 cycles subtracted",
+        )
+        .unwrap();
+
+        let mut first = KtestResult::new("test000001.ktest".to_owned(), None);
+        first.cycles = Some(100);
+        first.objects.push(InjectedObject {
+            name: "a".to_owned(),
+            address: 0x2000_0000,
+            bytes: vec![1, 0, 0, 0],
+            value: Some("1".to_owned()),
+        });
+        let mut hang = KtestResult::new("test000002.ktest".to_owned(), None);
+        hang.status = KtestStatus::Hang;
+        hang.set_error("cycle budget of 50 exceeded after 60 cycles".to_owned());
+        let mut second = KtestResult::new("test000003.ktest".to_owned(), None);
+        second.cycles = Some(150);
+        files.write(first).unwrap();
+        files.write(hang).unwrap();
+        files.write(second).unwrap();
+        files.finish().unwrap();
+
+        let text = fs::read_to_string(files.text_path()).unwrap();
+        let results = read_text_results(files.text_path()).unwrap();
+        drop(files);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(text.contains("WCET summary"));
+        let results: Vec<(&str, Option<u64>)> = results
+            .iter()
+            .map(|r| (r.ktest.as_str(), r.cycles))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("test000001.ktest", Some(100)),
+                ("test000003.ktest", Some(150))
+            ]
+        );
+    }
+}
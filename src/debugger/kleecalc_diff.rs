@@ -0,0 +1,331 @@
+use super::kleecalc::{read_text_results, KtestResult, ResultSet};
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+
+// How much slower a ktest may get before it counts as a regression
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    Cycles(u64),
+    Percent(f64),
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Threshold::Cycles(0)
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = anyhow::Error;
+
+    // "100" is 100 cycles and "5%" is 5 percent of the old cycles
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(p) if p >= 0.0 => Ok(Threshold::Percent(p)),
+                _ => Err(anyhow!("Invalid percentage threshold {:?}", s)),
+            },
+            None => s
+                .trim()
+                .parse::<u64>()
+                .map(Threshold::Cycles)
+                .map_err(|_| anyhow!("Invalid cycle threshold {:?}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Threshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Threshold::Cycles(cycles) => write!(f, "{} cycles", cycles),
+            Threshold::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl Threshold {
+    fn is_exceeded(&self, old: u64, new: u64) -> bool {
+        match self {
+            Threshold::Cycles(cycles) => new > old.saturating_add(*cycles),
+            Threshold::Percent(percent) => new as f64 > old as f64 * (1.0 + percent / 100.0),
+        }
+    }
+}
+
+// The measured cycles of a ktest in the old and new result set
+#[derive(Debug, Clone)]
+pub struct KtestDiff {
+    pub ktest: String,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+impl KtestDiff {
+    pub fn delta(&self) -> Option<i64> {
+        Some(self.new? as i64 - self.old? as i64)
+    }
+
+    pub fn percent(&self) -> Option<f64> {
+        match self.old? {
+            0 => None,
+            old => Some(self.delta()? as f64 * 100.0 / old as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResultDiff {
+    pub threshold: Threshold,
+    // Ktests in the order of the new result set, followed by those only in the old one
+    pub diffs: Vec<KtestDiff>,
+    pub old_worst: Option<(String, u64)>,
+    pub new_worst: Option<(String, u64)>,
+}
+
+impl ResultDiff {
+    pub fn new(old: &[KtestResult], new: &[KtestResult], threshold: Threshold) -> ResultDiff {
+        let mut diffs: Vec<KtestDiff> = new
+            .iter()
+            .map(|result| KtestDiff {
+                ktest: result.ktest.clone(),
+                old: old
+                    .iter()
+                    .find(|o| o.ktest == result.ktest)
+                    .and_then(|o| o.cycles),
+                new: result.cycles,
+            })
+            .collect();
+        for result in old {
+            if !new.iter().any(|n| n.ktest == result.ktest) {
+                diffs.push(KtestDiff {
+                    ktest: result.ktest.clone(),
+                    old: result.cycles,
+                    new: None,
+                });
+            }
+        }
+
+        ResultDiff {
+            threshold,
+            diffs,
+            old_worst: worst_case(old),
+            new_worst: worst_case(new),
+        }
+    }
+
+    // Ktests that got slower than the threshold allows
+    pub fn exceeded(&self) -> Vec<&KtestDiff> {
+        self.diffs
+            .iter()
+            .filter(|diff| match (diff.old, diff.new) {
+                (Some(old), Some(new)) => self.threshold.is_exceeded(old, new),
+                _ => false,
+            })
+            .collect()
+    }
+
+    // Ktests that now take longer than the old worst case
+    pub fn new_worst_cases(&self) -> Vec<&KtestDiff> {
+        let old_max = match &self.old_worst {
+            Some((_, cycles)) => *cycles,
+            None => return vec![],
+        };
+        self.diffs
+            .iter()
+            .filter(|diff| diff.new.map_or(false, |new| new > old_max))
+            .collect()
+    }
+
+    // A regression is a ktest or the worst case getting slower than the threshold allows
+    pub fn is_regression(&self) -> bool {
+        let worst_case_exceeded = match (&self.old_worst, &self.new_worst) {
+            (Some((_, old)), Some((_, new))) => self.threshold.is_exceeded(*old, *new),
+            _ => false,
+        };
+        worst_case_exceeded || !self.exceeded().is_empty()
+    }
+}
+
+impl std::fmt::Display for ResultDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Per ktest cycles (old -> new):")?;
+        for diff in &self.diffs {
+            write!(
+                f,
+                "\t{}: {} -> {}",
+                diff.ktest,
+                format_cycles(diff.old),
+                format_cycles(diff.new)
+            )?;
+            if let Some(delta) = diff.delta() {
+                write!(f, " ({:+}", delta)?;
+                if let Some(percent) = diff.percent() {
+                    write!(f, ", {:+.1}%", percent)?;
+                }
+                write!(f, ")")?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "Worst case: {} -> {}",
+            format_worst_case(&self.old_worst),
+            format_worst_case(&self.new_worst)
+        )?;
+        let new_worst_cases = self.new_worst_cases();
+        if !new_worst_cases.is_empty() {
+            writeln!(f, "New worst cases:")?;
+            for diff in new_worst_cases {
+                writeln!(f, "\t{}: {}", diff.ktest, format_cycles(diff.new))?;
+            }
+        }
+
+        let exceeded = self.exceeded();
+        match exceeded.is_empty() {
+            true => writeln!(f, "No ktest exceeds the threshold of {}", self.threshold)?,
+            false => {
+                writeln!(f, "Ktests exceeding the threshold of {}:", self.threshold)?;
+                for diff in exceeded {
+                    writeln!(
+                        f,
+                        "\t{}: {:+}",
+                        diff.ktest,
+                        diff.delta().unwrap_or_default()
+                    )?;
+                }
+            }
+        };
+        match self.is_regression() {
+            true => write!(f, "Regression found"),
+            false => write!(f, "No regression"),
+        }
+    }
+}
+
+// Load the results of a kleecalc run from its .json file or .cycles text file
+pub fn load_results(path: &Path) -> Result<Vec<KtestResult>> {
+    match path.extension().map_or(false, |e| e == "json") {
+        true => {
+            let file =
+                File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+            let set: ResultSet = serde_json::from_reader(file)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            Ok(set.results)
+        }
+        false => read_text_results(path),
+    }
+}
+
+pub fn diff_result_files(old: &Path, new: &Path, threshold: Threshold) -> Result<ResultDiff> {
+    let old = load_results(old)?;
+    let new = load_results(new)?;
+    Ok(ResultDiff::new(&old, &new, threshold))
+}
+
+fn worst_case(results: &[KtestResult]) -> Option<(String, u64)> {
+    results
+        .iter()
+        .filter_map(|result| Some((result.ktest.clone(), result.cycles?)))
+        .max_by_key(|(_, cycles)| *cycles)
+}
+
+fn format_cycles(cycles: Option<u64>) -> String {
+    match cycles {
+        Some(cycles) => cycles.to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+fn format_worst_case(worst: &Option<(String, u64)>) -> String {
+    match worst {
+        Some((ktest, cycles)) => format!("{} ({})", cycles, ktest),
+        None => "-".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(ktest: &str, cycles: Option<u64>) -> KtestResult {
+        let mut result = KtestResult::new(ktest.to_owned(), None);
+        result.cycles = cycles;
+        result
+    }
+
+    #[test]
+    fn parse_threshold() {
+        assert_eq!("100".parse::<Threshold>().unwrap(), Threshold::Cycles(100));
+        assert_eq!("5%".parse::<Threshold>().unwrap(), Threshold::Percent(5.0));
+        assert_eq!(
+            " 2.5 %".parse::<Threshold>().unwrap(),
+            Threshold::Percent(2.5)
+        );
+        assert!("-5%".parse::<Threshold>().is_err());
+        assert!("-5".parse::<Threshold>().is_err());
+        assert!("fast".parse::<Threshold>().is_err());
+    }
+
+    #[test]
+    fn threshold_exceeded() {
+        assert!(!Threshold::Cycles(10).is_exceeded(100, 110));
+        assert!(Threshold::Cycles(10).is_exceeded(100, 111));
+        assert!(!Threshold::Cycles(u64::MAX).is_exceeded(100, u64::MAX));
+        assert!(!Threshold::Percent(5.0).is_exceeded(100, 105));
+        assert!(Threshold::Percent(5.0).is_exceeded(100, 106));
+        assert!(!Threshold::default().is_exceeded(100, 90));
+    }
+
+    #[test]
+    fn ktests_in_one_result_set() {
+        let old = vec![result("a", Some(100)), result("b", Some(50))];
+        let new = vec![result("b", Some(50)), result("c", Some(80))];
+        let diff = ResultDiff::new(&old, &new, Threshold::default());
+
+        let ktests: Vec<(&str, Option<u64>, Option<u64>)> = diff
+            .diffs
+            .iter()
+            .map(|d| (d.ktest.as_str(), d.old, d.new))
+            .collect();
+        assert_eq!(
+            ktests,
+            vec![
+                ("b", Some(50), Some(50)),
+                ("c", None, Some(80)),
+                ("a", Some(100), None)
+            ]
+        );
+        assert!(diff.exceeded().is_empty());
+        assert!(!diff.is_regression());
+    }
+
+    #[test]
+    fn worst_case_regression() {
+        // No ktest got slower, but a new ktest is slower than the old worst case
+        let old = vec![result("a", Some(100)), result("b", Some(50))];
+        let new = vec![result("b", Some(50)), result("c", Some(200))];
+        let diff = ResultDiff::new(&old, &new, Threshold::Percent(10.0));
+
+        assert!(diff.exceeded().is_empty());
+        let new_worst: Vec<&str> = diff
+            .new_worst_cases()
+            .iter()
+            .map(|d| d.ktest.as_str())
+            .collect();
+        assert_eq!(new_worst, vec!["c"]);
+        assert!(diff.is_regression());
+    }
+
+    #[test]
+    fn ktest_regression() {
+        let old = vec![result("a", Some(100))];
+        let new = vec![result("a", Some(120))];
+        let diff = ResultDiff::new(&old, &new, Threshold::Cycles(10));
+        assert_eq!(diff.exceeded().len(), 1);
+        assert_eq!(diff.exceeded()[0].delta(), Some(20));
+        assert!(diff.is_regression());
+    }
+}
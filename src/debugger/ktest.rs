@@ -0,0 +1,462 @@
+use super::kleecalc::KtestObject;
+use anyhow::{anyhow, Context, Result};
+use gimli::{
+    AttributeValue, Dwarf, EvaluationResult, Expression, Location, Piece, Reader, Unit, UnitOffset,
+};
+use ktest_parser::parse_ktest;
+use rust_debug::call_stack::MemoryAccess;
+use rust_debug::evaluate::evaluate;
+use rust_debug::evaluate::evaluate::EvaluatorValue;
+use rust_debug::registers::Registers;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"KTEST";
+// The version KLEE writes, versions before 2 have no symbolic argv fields
+const VERSION: u32 = 3;
+
+// A KLEE .ktest file, all numbers are stored big endian
+#[derive(Debug, Clone, Default)]
+pub struct Ktest {
+    pub args: Vec<String>,
+    pub sym_argvs: u32,
+    pub sym_argv_len: u32,
+    pub objects: Vec<KtestObject>,
+}
+
+impl Ktest {
+    pub fn read(path: &Path) -> Result<Ktest> {
+        let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let ktest =
+            parse_ktest(&data).with_context(|| format!("Invalid ktest file {}", path.display()))?;
+        Ok(Ktest {
+            args: ktest.args,
+            sym_argvs: ktest.sym_argvs as u32,
+            sym_argv_len: ktest.sym_argv_len as u32,
+            objects: ktest
+                .objects
+                .into_iter()
+                .map(|object| KtestObject {
+                    name: object.name,
+                    bytes: object.bytes,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&(self.args.len() as u32).to_be_bytes());
+        for arg in &self.args {
+            write_block(&mut data, arg.as_bytes());
+        }
+        data.extend_from_slice(&self.sym_argvs.to_be_bytes());
+        data.extend_from_slice(&self.sym_argv_len.to_be_bytes());
+        data.extend_from_slice(&(self.objects.len() as u32).to_be_bytes());
+        for object in &self.objects {
+            write_block(&mut data, object.name.as_bytes());
+            write_block(&mut data, &object.bytes);
+        }
+        data
+    }
+}
+
+fn write_block(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    data.extend_from_slice(bytes);
+}
+
+#[derive(Debug, Clone)]
+pub enum KtestCommand {
+    // Create a ktest with a zeroed object for each firmware variable
+    New {
+        path: PathBuf,
+        variables: Vec<String>,
+    },
+    Set {
+        path: PathBuf,
+        object: String,
+        value: String,
+    },
+    Show {
+        path: PathBuf,
+    },
+}
+
+// Run a ktest command and return the path and content of the ktest file.
+// Relative paths are relative to the ktests directory so kleecalc picks the files up.
+pub fn run_ktest_command<R: Reader<Offset = usize>>(
+    command: KtestCommand,
+    dwarf: Option<&Dwarf<R>>,
+    ktests_directory: Option<&Path>,
+) -> Result<(PathBuf, Ktest)> {
+    let resolve = |path: PathBuf| match ktests_directory {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path,
+    };
+
+    match command {
+        KtestCommand::New { path, variables } => {
+            let path = resolve(path);
+            if path.exists() {
+                return Err(anyhow!("{} already exists", path.display()));
+            }
+            let dwarf = dwarf.ok_or_else(|| anyhow!("Requires elf file path"))?;
+
+            let mut ktest = Ktest::default();
+            for name in variables {
+                let size = variable_byte_size(dwarf, &name)?
+                    .ok_or_else(|| anyhow!("No variable {:?} with a known size found", name))?;
+                ktest.objects.push(KtestObject {
+                    name,
+                    bytes: vec![0; size as usize],
+                });
+            }
+            ktest.write(&path)?;
+            Ok((path, ktest))
+        }
+        KtestCommand::Set {
+            path,
+            object,
+            value,
+        } => {
+            let path = resolve(path);
+            let mut ktest = Ktest::read(&path)?;
+            let target = ktest
+                .objects
+                .iter_mut()
+                .find(|o| o.name == object)
+                .ok_or_else(|| anyhow!("No ktest object named {:?}", object))?;
+            target.bytes = parse_object_value(&value, target.bytes.len())?;
+            ktest.write(&path)?;
+            Ok((path, ktest))
+        }
+        KtestCommand::Show { path } => {
+            let path = resolve(path);
+            let ktest = Ktest::read(&path)?;
+            Ok((path, ktest))
+        }
+    }
+}
+
+// "0x" followed by the bytes in memory order, or an integer stored little endian
+fn parse_object_value(value: &str, size: usize) -> Result<Vec<u8>> {
+    match value.strip_prefix("0x") {
+        Some(hex) => {
+            if !hex.is_ascii() || hex.len() != size * 2 {
+                return Err(anyhow!(
+                    "Expected {} bytes ({} hex digits), got {:?}",
+                    size,
+                    size * 2,
+                    value
+                ));
+            }
+            (0..size)
+                .map(|i| {
+                    u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                        .map_err(|_| anyhow!("Invalid hex value {:?}", value))
+                })
+                .collect()
+        }
+        None => {
+            let number: i128 = value
+                .parse()
+                .map_err(|_| anyhow!("Expected an integer or 0x followed by hex bytes"))?;
+            // negative values must fit signed, positive ones unsigned
+            let bits = size as u32 * 8;
+            let fits = match (size < 16, number < 0) {
+                (false, _) => true,
+                (true, true) => bits > 0 && number >> (bits - 1) == -1,
+                (true, false) => number >> bits == 0,
+            };
+            if !fits {
+                return Err(anyhow!("{} doesn't fit in {} bytes", number, size));
+            }
+            let mut bytes = number.to_le_bytes().to_vec();
+            // sign extend values larger than an i128
+            bytes.resize(size.max(16), if number < 0 { 0xff } else { 0 });
+            bytes.truncate(size);
+            Ok(bytes)
+        }
+    }
+}
+
+// Frame base used when a ktest object is evaluated as a local variable
+const FRAME_BASE: u64 = 0x2000_0000;
+
+// Memory holding nothing but the bytes of one ktest object at the address of its variable
+struct ObjectMemory<'a> {
+    address: u32,
+    bytes: &'a [u8],
+}
+
+impl MemoryAccess for ObjectMemory<'_> {
+    fn get_address(&mut self, address: &u32, num_bytes: usize) -> Option<Vec<u8>> {
+        let start = address.checked_sub(self.address)? as usize;
+        let bytes = self.bytes.get(start..start.checked_add(num_bytes)?)?;
+        Some(bytes.to_vec())
+    }
+}
+
+// Evaluate the bytes of a ktest object with the type and location of the firmware variable
+// of the same name, the memory of the variable holds the bytes and nothing else
+pub fn evaluate_object<R: Reader<Offset = usize>>(
+    dwarf: &Dwarf<R>,
+    object: &KtestObject,
+) -> Result<Option<EvaluatorValue<R>>> {
+    match find_variable(dwarf, |_, name, _| Ok(name == object.name))? {
+        Some((unit, type_offset, Some(location))) => {
+            evaluate_variable(dwarf, &unit, type_offset, location, &object.bytes)
+        }
+        _ => Ok(None),
+    }
+}
+
+// Evaluate bytes written to an address with the type of the static variable stored there
+pub fn evaluate_object_at<R: Reader<Offset = usize>>(
+    dwarf: &Dwarf<R>,
+    bytes: &[u8],
+    address: u32,
+) -> Result<Option<EvaluatorValue<R>>> {
+    let variable = find_variable(dwarf, |unit, _, location| match location {
+        Some(location) => Ok(location_address(unit, location.clone(), None)? == Some(address)),
+        None => Ok(false),
+    })?;
+    match variable {
+        Some((unit, type_offset, Some(location))) => {
+            evaluate_variable(dwarf, &unit, type_offset, location, bytes)
+        }
+        _ => Ok(None),
+    }
+}
+
+// Evaluate a variable as if its memory held the bytes, None if the sizes differ
+fn evaluate_variable<R: Reader<Offset = usize>>(
+    dwarf: &Dwarf<R>,
+    unit: &Unit<R>,
+    type_offset: UnitOffset,
+    location: Expression<R>,
+    bytes: &[u8],
+) -> Result<Option<EvaluatorValue<R>>> {
+    if type_byte_size(unit, type_offset)? != Some(bytes.len() as u64) {
+        return Ok(None);
+    }
+    let address = match location_address(unit, location.clone(), Some(FRAME_BASE))? {
+        Some(address) => address,
+        None => return Ok(None),
+    };
+    let type_die = unit.entry(type_offset)?;
+
+    let mut memory = ObjectMemory { address, bytes };
+    let value = evaluate(
+        dwarf,
+        unit,
+        0,
+        location,
+        Some(FRAME_BASE),
+        Some(unit),
+        Some(&type_die),
+        &Registers::new(),
+        &mut memory,
+    )?;
+    Ok(Some(value))
+}
+
+// Address of a variable stored in memory, None for locals when there is no frame base
+fn location_address<R: Reader<Offset = usize>>(
+    unit: &Unit<R>,
+    location: Expression<R>,
+    frame_base: Option<u64>,
+) -> Result<Option<u32>> {
+    let mut evaluation = location.evaluation(unit.encoding());
+    let mut result = evaluation.evaluate()?;
+    loop {
+        result = match (result, frame_base) {
+            (EvaluationResult::Complete, _) => break,
+            (EvaluationResult::RequiresRelocatedAddress(address), _) => {
+                evaluation.resume_with_relocated_address(address)?
+            }
+            (EvaluationResult::RequiresFrameBase, Some(frame_base)) => {
+                evaluation.resume_with_frame_base(frame_base)?
+            }
+            // Register values and the like, the object is not in memory
+            _ => return Ok(None),
+        };
+    }
+    match evaluation.result().as_slice() {
+        [Piece {
+            location: Location::Address { address },
+            ..
+        }] => Ok(Some(*address as u32)),
+        _ => Ok(None),
+    }
+}
+
+// Byte size of the type of the first variable in the DWARF with the name
+pub fn variable_byte_size<R: Reader<Offset = usize>>(
+    dwarf: &Dwarf<R>,
+    name: &str,
+) -> Result<Option<u64>> {
+    match find_variable(dwarf, |_, variable_name, _| Ok(variable_name == name))? {
+        Some((unit, offset, _)) => type_byte_size(&unit, offset),
+        None => Ok(None),
+    }
+}
+
+// The unit, type and location of the first typed variable in the DWARF that matches
+fn find_variable<R, F>(
+    dwarf: &Dwarf<R>,
+    mut matches: F,
+) -> Result<Option<(Unit<R>, UnitOffset, Option<Expression<R>>)>>
+where
+    R: Reader<Offset = usize>,
+    F: FnMut(&Unit<R>, &str, Option<&Expression<R>>) -> Result<bool>,
+{
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_variable {
+                continue;
+            }
+            let name = match entry.attr_value(gimli::DW_AT_name)? {
+                Some(attr) => dwarf.attr_string(&unit, attr)?,
+                None => continue,
+            };
+            let type_offset = match entry.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(offset)) => offset,
+                _ => continue,
+            };
+            let location = match entry.attr_value(gimli::DW_AT_location)? {
+                Some(AttributeValue::Exprloc(location)) => Some(location),
+                _ => None,
+            };
+            if matches(&unit, &name.to_string_lossy()?, location.as_ref())? {
+                return Ok(Some((unit, type_offset, location)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn type_byte_size<R: Reader<Offset = usize>>(
+    unit: &Unit<R>,
+    offset: UnitOffset,
+) -> Result<Option<u64>> {
+    let entry = unit.entry(offset)?;
+    if let Some(size) = entry
+        .attr_value(gimli::DW_AT_byte_size)?
+        .and_then(|size| size.udata_value())
+    {
+        return Ok(Some(size));
+    }
+    if entry.tag() == gimli::DW_TAG_pointer_type {
+        return Ok(Some(unit.header.address_size() as u64));
+    }
+    let inner = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(inner)) => inner,
+        _ => return Ok(None),
+    };
+
+    match entry.tag() {
+        gimli::DW_TAG_array_type => {
+            let element_size = match type_byte_size(unit, inner)? {
+                Some(size) => size,
+                None => return Ok(None),
+            };
+            let mut count = 1;
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let child = child.entry();
+                if child.tag() != gimli::DW_TAG_subrange_type {
+                    continue;
+                }
+                let child_count = child
+                    .attr_value(gimli::DW_AT_count)?
+                    .and_then(|c| c.udata_value());
+                let upper_bound = child
+                    .attr_value(gimli::DW_AT_upper_bound)?
+                    .and_then(|u| u.udata_value());
+                count *= match (child_count, upper_bound) {
+                    (Some(child_count), _) => child_count,
+                    (None, Some(upper_bound)) => upper_bound + 1,
+                    _ => return Ok(None),
+                };
+            }
+            Ok(Some(element_size * count))
+        }
+        gimli::DW_TAG_typedef
+        | gimli::DW_TAG_const_type
+        | gimli::DW_TAG_volatile_type
+        | gimli::DW_TAG_restrict_type
+        | gimli::DW_TAG_atomic_type => type_byte_size(unit, inner),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ktest_round_trip() {
+        let ktest = Ktest {
+            args: vec!["app.bc".to_owned()],
+            sym_argvs: 0,
+            sym_argv_len: 0,
+            objects: vec![
+                KtestObject {
+                    name: "a".to_owned(),
+                    bytes: vec![1, 2, 3, 4],
+                },
+                KtestObject {
+                    name: "empty".to_owned(),
+                    bytes: vec![],
+                },
+            ],
+        };
+        let parsed = parse_ktest(&ktest.to_bytes()).unwrap();
+        assert_eq!(parsed.args, ktest.args);
+        assert_eq!(parsed.objects.len(), 2);
+        assert_eq!(parsed.objects[0].name, "a");
+        assert_eq!(parsed.objects[0].bytes, vec![1, 2, 3, 4]);
+        assert_eq!(parsed.objects[1].name, "empty");
+        assert!(parsed.objects[1].bytes.is_empty());
+    }
+
+    #[test]
+    fn object_value_integers() {
+        assert_eq!(parse_object_value("258", 4).unwrap(), vec![2, 1, 0, 0]);
+        assert_eq!(parse_object_value("-1", 2).unwrap(), vec![0xff, 0xff]);
+        assert_eq!(parse_object_value("255", 1).unwrap(), vec![0xff]);
+        assert_eq!(parse_object_value("-128", 1).unwrap(), vec![0x80]);
+        assert_eq!(parse_object_value("-2", 20).unwrap(), {
+            let mut bytes = vec![0xff; 20];
+            bytes[0] = 0xfe;
+            bytes
+        });
+    }
+
+    #[test]
+    fn object_value_overflow() {
+        assert!(parse_object_value("256", 1).is_err());
+        assert!(parse_object_value("-129", 1).is_err());
+        assert!(parse_object_value("1", 0).is_err());
+        assert!(parse_object_value("0", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn object_value_hex() {
+        assert_eq!(parse_object_value("0x0102", 2).unwrap(), vec![1, 2]);
+        assert!(parse_object_value("0x01", 2).is_err());
+        assert!(parse_object_value("0xzz", 1).is_err());
+    }
+}
@@ -1,16 +1,33 @@
+pub mod bkpt_handler;
+pub mod bkpt_protocol;
 pub mod config;
+pub mod kleecalc;
+pub mod kleecalc_diff;
+pub mod ktest;
 
+use bkpt_handler::{default_bkpt_handlers, BkptHandlers, BkptOutcome, ResultSink};
+use bkpt_protocol::{BkptAction, BkptProtocol};
 use config::Config;
-use ktest_parser::{self, parse_ktest};
+use kleecalc::{
+    add_region_cycles, deepest_stack_write, exception_number, file_name, find_err_file, find_symbols,
+    format_location, list_err_files, list_ktests, paint_stack, ram_ranges, read_c_string,
+    read_err_file, read_ktest_objects, write_ktest_object, CheckpointCycles, CycleCounter,
+    CycleStamp, ErrorVerification, ExceptionState, FaultKind, InjectedObject, KleeCalcOptions,
+    KtestObject, KtestResult, KtestStatus, RegionCycles, ResultFiles, ResultHeader, RunningTime,
+    StackBounds, TargetSnapshot,
+};
+
+use kleecalc_diff::{diff_result_files, Threshold};
+use ktest::{evaluate_object, evaluate_object_at, run_ktest_command, KtestCommand};
 
 use rust_debug::call_stack::{CallFrame, MemoryAccess};
 use rust_debug::evaluate::evaluate::{get_udata, EvaluatorValue};
 use rust_debug::registers::Registers;
 use rust_debug::source_information::{find_breakpoint_location, SourceInformation};
 
-use std::fs::{File, ReadDir};
-use std::io::Write;
+use std::fs::ReadDir;
 use std::num::NonZeroU64;
+use std::ops::Range;
 
 use gimli::DebugFrame;
 use gimli::Dwarf;
@@ -26,12 +43,13 @@ use anyhow::{anyhow, Context, Result};
 use capstone::arch::BuildsCapstone;
 use crossbeam_channel::{Receiver, Sender, TryRecvError};
 use debugserver_types::{Breakpoint, SourceBreakpoint};
+use chrono::Local;
 use log::{error, info, warn};
 use probe_rs::flashing::{download_file, Format};
-use probe_rs::{CoreStatus, MemoryInterface};
+use probe_rs::{CoreStatus, HaltReason, MemoryInterface};
 use regex::Regex;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 use std::{fs, vec, env};
 
@@ -110,6 +128,22 @@ impl DebugHandler {
                 self.config.ktests_directory = Some(cwd);
                 Ok((false, DebugResponse::SetKtestFolder))
             }
+            DebugRequest::KleeCalcDiff {
+                old,
+                new,
+                threshold,
+            } => Ok((false, klee_calc_diff_command(&old, &new, threshold)?)),
+            DebugRequest::Ktest { command } => {
+                let ktests_directory = self.config.ktests_directory.as_ref().map(PathBuf::from);
+                let dwarf = match &self.config.elf_file_path {
+                    Some(path) => Some(read_dwarf(path)?.0),
+                    None => None,
+                };
+                Ok((
+                    false,
+                    ktest_command(command, dwarf.as_ref(), ktests_directory.as_deref())?,
+                ))
+            }
             _ => {
                 if self.config.is_missing_config() {
                     return Ok((
@@ -167,6 +201,58 @@ impl DebugHandler {
     }
 }
 
+// Compare two kleecalc result files, it doesn't need a target
+fn klee_calc_diff_command(old: &Path, new: &Path, threshold: Threshold) -> Result<DebugResponse> {
+    let diff = diff_result_files(old, new, threshold)?;
+    Ok(DebugResponse::KleeCalcDiff {
+        report: diff.to_string(),
+        regression: diff.is_regression(),
+    })
+}
+
+// The event telling that kleecalc stopped, with the results written so far
+fn klee_calc_finished_event(results: &Option<ResultFiles>, error: Option<String>) -> DebugEvent {
+    let (results_path, results) = match results {
+        Some(results) => (
+            Some(results.text_path().to_path_buf()),
+            results.results().to_vec(),
+        ),
+        None => (None, vec![]),
+    };
+    DebugEvent::KleeCalcFinished {
+        results_path,
+        results,
+        error,
+    }
+}
+
+// Run a ktest command and decode the objects with the types of the variables in the elf
+fn ktest_command<R: Reader<Offset = usize>>(
+    command: KtestCommand,
+    dwarf: Option<&Dwarf<R>>,
+    ktests_directory: Option<&Path>,
+) -> Result<DebugResponse> {
+    let (path, ktest) = run_ktest_command(command, dwarf, ktests_directory)?;
+
+    let mut objects = vec![];
+    for object in ktest.objects {
+        let value = match dwarf {
+            Some(dwarf) => match evaluate_object(dwarf, &object) {
+                Ok(value) => value
+                    .map(|value| Variable::from_value(object.name.clone(), &value))
+                    .transpose()?,
+                Err(err) => {
+                    warn!("Failed to decode ktest object {}: {}", object.name, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        objects.push((object, value));
+    }
+    Ok(DebugResponse::Ktest { path, objects })
+}
+
 pub fn init(
     sender: &mut Sender<Command>,
     receiver: &mut Receiver<DebugRequest>,
@@ -221,11 +307,32 @@ pub fn init(
         variables: None,
         id_gen: IdGen::new(),
         trace: false,
-        klee_trace_start: 0,
+        klee_options: KleeCalcOptions::default(),
+        overhead_cycles: None,
+        cycle_counter: CycleCounter::new(),
+        klee_trace_start: CycleStamp::default(),
+        klee_trace_start_pc: 0,
+        ktest_running_time: RunningTime::default(),
         ktests_run: 0,
-        result_filepath: PathBuf::new(),
+        results: None,
         ktests_directory: ktests_directory,
-        skip: false,
+        ktests: vec![],
+        err_files: BTreeMap::new(),
+        snapshot: None,
+        fault_handlers: vec![],
+        events: vec![],
+        ktest_objects: None,
+        ktest_result: None,
+        ktest_path: None,
+        open_regions: vec![],
+        regions: vec![],
+        checkpoints: vec![],
+        bkpt_protocol: BkptProtocol::default(),
+        bkpt_handlers: default_bkpt_handlers(),
+        stack_bounds: None,
+        painted_stack: None,
+        chip,
+        probe_number,
     };
 
     debugger.run(sender, receiver, request)
@@ -248,11 +355,36 @@ struct Debugger<'a, R: Reader<Offset = usize>> {
     scopes: Option<HashMap<i64, Vec<debugserver_types::Scope>>>,
     variables: Option<HashMap<i64, Vec<Variable>>>,
     trace: bool,
-    klee_trace_start: u32,
+    klee_options: KleeCalcOptions,
+    overhead_cycles: Option<u64>,
+    cycle_counter: CycleCounter,
+    klee_trace_start: CycleStamp,
+    klee_trace_start_pc: u32,
+    // Running time of the ktest for the timeout
+    ktest_running_time: RunningTime,
     ktests_run: u32,
-    result_filepath: PathBuf,
+    results: Option<ResultFiles>,
     ktests_directory: PathBuf,
-    skip: bool,
+    ktests: Vec<PathBuf>,
+    // .err files of the ktests directory by file name
+    err_files: BTreeMap<String, PathBuf>,
+    snapshot: Option<TargetSnapshot>,
+    fault_handlers: Vec<(u32, FaultKind)>,
+    // Events raised while tracing, sent when the debugger next checks the core
+    events: Vec<DebugEvent>,
+    ktest_objects: Option<Vec<KtestObject>>,
+    ktest_result: Option<KtestResult>,
+    ktest_path: Option<PathBuf>,
+    open_regions: Vec<(String, CycleStamp)>,
+    regions: Vec<RegionCycles>,
+    checkpoints: Vec<CheckpointCycles>,
+    bkpt_protocol: BkptProtocol,
+    bkpt_handlers: BkptHandlers,
+    // Stack limits and the stack range painted at the start of the running ktest
+    stack_bounds: Option<StackBounds>,
+    painted_stack: Option<Range<u32>>,
+    chip: String,
+    probe_number: usize,
 }
 
 impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
@@ -298,7 +430,12 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
                 Err(err) => {
                     match err {
                         // commands to process, to check if halted
-                        TryRecvError::Empty => self.check_halted(sender)?,
+                        TryRecvError::Empty => {
+                            if let Err(err) = self.check_halted(sender) {
+                                self.klee_calc_failed(sender, &err)?;
+                                return Err(err);
+                            }
+                        }
                         TryRecvError::Disconnected => {
                             let mut core = self.session.core(0)?;
                             core.clear_all_hw_breakpoints()?;
@@ -320,12 +457,33 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
         self.variables = None;
     }
 
+    // Tell the CLI or DAP that an error ended kleecalc, it only gets the error response otherwise
+    fn klee_calc_failed(&mut self, sender: &mut Sender<Command>, err: &anyhow::Error) -> Result<()> {
+        if !self.trace {
+            return Ok(());
+        }
+        self.trace = false;
+        if let Some(results) = self.results.as_mut() {
+            if let Err(e) = results.flush() {
+                eprintln!("Couldn't write to file: {}", e);
+            }
+        }
+        sender.send(Command::Event(klee_calc_finished_event(
+            &self.results,
+            Some(format!("{:?}", err)),
+        )))?;
+        Ok(())
+    }
+
     fn check_halted(&mut self, sender: &mut Sender<Command>) -> Result<()> {
         let delta = Duration::from_millis(400);
         if self.running && self.check_time.elapsed() > delta {
             self.check_time = Instant::now();
             self.process_halt_event(sender)?;
         }
+        for event in self.events.drain(..) {
+            sender.send(Command::Event(event))?;
+        }
 
         Ok(())
     }
@@ -336,9 +494,52 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
 
         if let CoreStatus::Halted(reason) = status {
             self.running = false;
+            self.ktest_running_time.halted();
 
             let pc = core.read_core_reg(core.registers().program_counter())?;
 
+            // registered bkpt handlers come before trace mode and the user, unless the bkpt
+            // number is mapped to a trace action
+            if let Ok(nr) = read_bkpt(&mut core, pc) {
+                let traced = self.trace && self.bkpt_protocol.action(nr).is_some();
+                if let (false, Some(handler)) = (traced, self.bkpt_handlers.get_mut(nr)) {
+                    if self.trace {
+                        // the halt is counted so its overhead is subtracted like other bkpts
+                        let cyccnt = read_cyccnt(&mut core)?;
+                        self.cycle_counter.sample_halted(cyccnt);
+                    }
+                    let mut sink = ResultSink::new(handler.name(), pc);
+                    let outcome = match handler.handle(&mut core, pc, &mut sink) {
+                        Ok(outcome) => outcome,
+                        Err(err) => {
+                            warn!("bkpt #{} handler {} failed: {:?}", nr, handler.name(), err);
+                            sink.record("error", err);
+                            BkptOutcome::Halt
+                        }
+                    };
+                    drop(core);
+                    for record in sink.into_records() {
+                        if let Some(result) = self.ktest_result.as_mut() {
+                            result.records.push(record.clone());
+                        }
+                        sender.send(Command::Event(DebugEvent::BkptRecord { record }))?;
+                    }
+                    match outcome {
+                        BkptOutcome::Resume => {
+                            self.continue_command()?;
+                        }
+                        BkptOutcome::Halt => {
+                            sender.send(Command::Event(DebugEvent::Halted {
+                                pc: pc,
+                                reason: reason,
+                                hit_breakpoint_ids: None,
+                            }))?;
+                        }
+                    };
+                    return Ok(());
+                }
+            }
+
             let mut hit_breakpoint_ids = vec![];
             match self.breakpoints.get(&pc) {
                 Some(bkpt) => hit_breakpoint_ids.push(match bkpt.id {
@@ -363,6 +564,16 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
                 Ok(())
             }
         } else {
+            if self.trace {
+                // Sample the cycle counter while running so that no wrap is missed
+                let cyccnt = read_cyccnt(&mut core)?;
+                let cycle_counter = self.cycle_counter.sample(cyccnt);
+                drop(core);
+                self.ktest_running_time.running();
+                if let Some(reason) = self.ktest_limit_exceeded(&cycle_counter) {
+                    self.hang_event(reason)?;
+                }
+            }
             Ok(())
         }
     }
@@ -406,7 +617,19 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
             DebugRequest::DAPVariables { id } => self.dap_variables(id),
 
             DebugRequest::CycleCounter => self.cycle_counter_command(),
-            DebugRequest::KleeCalc => self.klee_calc_command(),
+            DebugRequest::KleeCalc { options } => self.klee_calc_command(options),
+            DebugRequest::KleeCalcDiff {
+                old,
+                new,
+                threshold,
+            } => Ok(Command::Response(klee_calc_diff_command(
+                &old, &new, threshold,
+            )?)),
+            DebugRequest::Ktest { command } => Ok(Command::Response(ktest_command(
+                command,
+                Some(self.debug_info.dwarf),
+                Some(self.ktests_directory.as_path()),
+            )?)),
 
             _ => Ok(Command::Request(request)),
         }
@@ -763,6 +986,8 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
         if status.is_halted() {
             let _pc = continue_fix(&mut core, &self.breakpoints)?;
             core.run()?;
+            self.cycle_counter.resumed();
+            self.ktest_running_time.resumed();
             self.running = true;
             status = core.status()?;
 
@@ -1069,6 +1294,16 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
     // A simple example of a custom command
     fn cycle_counter_command(&mut self) -> Result<Command> {
         let mut core = self.session.core(0)?;
+        if enable_cycle_counter(&mut core)? {
+            println!("Cycle counter was disabled, it is now enabled");
+        }
+        if !core.status()?.is_halted() {
+            let first = read_cyccnt(&mut core)?;
+            std::thread::sleep(Duration::from_millis(10));
+            if read_cyccnt(&mut core)? == first {
+                println!("Warning: the cycle counter is not advancing");
+            }
+        }
         let (pc_val, cycle_counter) = read_cycle_counter(&mut core)?;
         println!("pc: {:#010x}, cycle counter: {}", pc_val, cycle_counter);
         drop(core);
@@ -1076,132 +1311,788 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
     }
 
     // A more advanced stateful command
-    fn klee_calc_command(&mut self) -> Result<Command> {
+    fn klee_calc_command(&mut self, options: KleeCalcOptions) -> Result<Command> {
+        let mut core = self.session.core(0)?;
+        if enable_cycle_counter(&mut core)? {
+            println!("Cycle counter was disabled, it is now enabled");
+        }
+        drop(core);
+
+        let mut protocol = BkptProtocol::default();
+        if let Some(path) = &options.bkpt_config {
+            protocol.load(path)?;
+        }
+        for (nr, action) in &options.bkpt_actions {
+            protocol.set(*nr, action.clone());
+        }
+        if options.bkpt_config.is_some() || !options.bkpt_actions.is_empty() {
+            for (nr, action) in protocol.mappings() {
+                println!("bkpt #{}: {}", nr, action);
+            }
+        }
+        self.bkpt_protocol = protocol;
+
+        self.painted_stack = None;
+        self.stack_bounds = match options.stack_usage {
+            true => {
+                let bounds = StackBounds::find(&self.file_path)?;
+                println!("Measuring stack usage down to {:#010x}", bounds.limit);
+                Some(bounds)
+            }
+            false => None,
+        };
+
+        //ktests directory holds all the ktests
+        self.ktests = list_ktests(&self.ktests_directory, &options)?;
+        self.err_files = list_err_files(&self.ktests_directory)?;
+        println!("{} ktests to run", self.ktests.len());
+
+        self.overhead_cycles = None;
+        if options.calibrate {
+            let overhead = self.calibrate_overhead()?;
+            println!("Breakpoint overhead: {} cycles", overhead);
+            self.overhead_cycles = Some(overhead);
+        }
+
+        // start a new run
+        self.ktests_run = 0;
+        self.results = None;
+        self.ktest_result = None;
+        self.ktest_path = None;
+        self.ktest_objects = None;
+        self.snapshot = None;
+
+        let mut core = self.session.core(0)?;
+        // fault exceptions halt the core so the ktest can be marked as faulted
+        set_vector_catch(&mut core, true)?;
+        for (address, _) in self.fault_handlers.drain(..) {
+            core.clear_hw_breakpoint(address)?;
+        }
+        if options.verify_errors {
+            // stop in the panic handler and HardFault to see if the predicted errors happen
+            let symbols = find_symbols(
+                &self.file_path,
+                &["rust_begin_unwind", "HardFaultTrampoline", "HardFault"],
+            )?;
+            for (name, address) in symbols {
+                let kind = match name.as_str() {
+                    "rust_begin_unwind" => FaultKind::Panic,
+                    _ => FaultKind::HardFault,
+                };
+                core.set_hw_breakpoint(address)?;
+                println!("Detecting {} at {} {:#010x}", kind, name, address);
+                self.fault_handlers.push((address, kind));
+            }
+            if !self.fault_handlers.iter().any(|(_, k)| *k == FaultKind::Panic) {
+                warn!("No panic handler found, panics can't be detected");
+            }
+        }
+        drop(core);
+
+        if let Some(path) = &options.resume {
+            // a bare file name is looked up among the earlier results
+            let path = match path.exists() {
+                true => path.clone(),
+                false => self.workdir.join("ktests_results").join(path),
+            };
+            let results = ResultFiles::open(path, self.result_header())?;
+            let total = self.ktests.len();
+            self.ktests.retain(|ktest| !results.contains(&file_name(ktest)));
+            println!(
+                "Resuming {}: {} ktests already done, {} left",
+                results.text_path().display(),
+                total - self.ktests.len(),
+                self.ktests.len()
+            );
+            self.results = Some(results);
+        }
+
+        self.events.push(DebugEvent::KleeCalcStarted {
+            total: self.ktests.len(),
+        });
+
         // set trace mode state
         self.trace = true;
+        self.klee_options = options;
         // continue execution
+        self.continue_klee_calc(false)
+    }
+
+    fn result_header(&self) -> ResultHeader {
+        ResultHeader {
+            elf_file: self.file_path.display().to_string(),
+            chip: self.chip.clone(),
+            probe: self.probe_number,
+            timestamp: Local::now().to_rfc3339(),
+            overhead_cycles: self.overhead_cycles,
+        }
+    }
+
+    // Continue to the next ktest run, from a fresh reset if the target is reset between runs.
+    // `reset` forces a reset, e.g. when the core can't continue after a fault, also before a snapshot
+    // is restored.
+    fn continue_klee_calc(&mut self, reset: bool) -> Result<Command> {
+        if reset || self.klee_options.reset_target {
+            self.clear_temporaries();
+
+            // a reset also clears the exception state and fault status a fault leaves behind
+            let mut core = self.session.core(0)?;
+            core.reset_and_halt(std::time::Duration::from_millis(10))
+                .context("Failed to reset and halt the core")?;
+            drop(core);
+
+            // CYCCNT may have been cleared by the reset
+            self.cycle_counter.restart();
+        }
+        if self.snapshot.is_some() {
+            self.clear_temporaries();
+
+            // the snapshot halts the core at the first bkpt #2 again
+            let mut core = self.session.core(0)?;
+            if let Some(snapshot) = &self.snapshot {
+                snapshot.restore(&mut core)?;
+            }
+            let pc = core.read_core_reg(core.registers().program_counter())?;
+            drop(core);
+
+            self.trace_event(pc)?;
+            return Ok(Command::Response(DebugResponse::Continue));
+        }
         self.continue_command()
     }
 
+    // Measure the cycles of an empty bkpt #2/bkpt #1 region by running it from RAM.
+    // The instructions are put well below the stack pointer and run with interrupts masked so
+    // no exception frame is stacked over them. The core is restored afterwards.
+    fn calibrate_overhead(&mut self) -> Result<u64> {
+        const RUNS: usize = 8;
+        // bkpt #2, bkpt #1
+        const CODE: [u8; 4] = [0x02, 0xbe, 0x01, 0xbe];
+        // Distance below the stack pointer the instructions are put at
+        const CODE_OFFSET: u32 = 0x400;
+
+        let ram = ram_ranges(&self.session.target().memory_map);
+        let mut core = self.session.core(0)?;
+        if !core.status()?.is_halted() {
+            core.halt(Duration::from_millis(100))?;
+        }
+
+        let pc = core.registers().program_counter();
+        let saved_pc = core.read_core_reg(pc)?;
+        let sp = core.read_core_reg(core.registers().stack_pointer())?;
+        let address = sp.wrapping_sub(CODE_OFFSET) & !0x3;
+        if !ram
+            .iter()
+            .any(|range| range.contains(&address) && range.contains(&sp))
+        {
+            return Err(anyhow!(
+                "No RAM {:#x} bytes below the stack pointer {:#010x} to calibrate in",
+                CODE_OFFSET,
+                sp
+            ));
+        }
+        let saved_mask = core.read_core_reg(PRIMASK_REG)?;
+        core.write_core_reg(PRIMASK_REG.into(), saved_mask | PRIMASK)?;
+        let mut saved_code = [0u8; 4];
+        core.read_8(address, &mut saved_code)?;
+        core.write_8(address, &CODE)?;
+
+        let mut overhead = u64::MAX;
+        let mut error = None;
+        for _ in 0..RUNS {
+            match measure_empty_region(&mut core, address) {
+                Ok(cycles) => overhead = overhead.min(cycles),
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            };
+        }
+
+        // Restore the core even if the calibration failed
+        core.write_8(address, &saved_code)?;
+        core.write_core_reg(pc.into(), saved_pc)?;
+        core.write_core_reg(PRIMASK_REG.into(), saved_mask)?;
+        let cyccnt = read_cyccnt(&mut core)?;
+        self.cycle_counter.sample(cyccnt);
+
+        match error {
+            Some(err) => Err(err.context("Failed to calibrate the breakpoint overhead")),
+            None => Ok(overhead),
+        }
+    }
+
+    // The core stopped in the panic handler or HardFault, the ktest can't reach bkpt #1
+    fn fault_event(
+        &mut self,
+        pc_val: u32,
+        kind: FaultKind,
+        exception: Option<ExceptionState>,
+    ) -> Result<()> {
+        let mut result = match self.ktest_result.take() {
+            Some(result) => result,
+            None => {
+                println!("Halted on a {} at {:#010x} outside of a ktest", kind, pc_val);
+                if let Some(exception) = &exception {
+                    println!("{}", exception);
+                }
+                self.trace = false;
+                let mut core = self.session.core(0)?;
+                set_vector_catch(&mut core, false)?;
+                if let Some(results) = self.results.as_mut() {
+                    results.flush()?;
+                }
+                self.events.push(klee_calc_finished_event(
+                    &self.results,
+                    Some(format!("{} at {:#010x} outside of a ktest", kind, pc_val)),
+                ));
+                return Ok(());
+            }
+        };
+        println!("{}: {} at {:#010x}", result.ktest, kind, pc_val);
+        if let Some(exception) = &exception {
+            println!("{}", exception);
+        }
+        result.exception = exception;
+
+        let frames = self.record_stack(&mut result);
+        result.end_pc = Some(pc_val);
+        match result.verification.as_mut() {
+            Some(verification) => {
+                let matching = frames.iter().find(|(_, file, line)| match (file, line) {
+                    (Some(file), Some(line)) => verification.predicted.matches(file, *line),
+                    _ => false,
+                });
+                let location = matching
+                    .or_else(|| frames.iter().find(|(_, f, l)| f.is_some() && l.is_some()));
+                verification.fault = Some(kind);
+                verification.location_matches = matching.is_some();
+                verification.location =
+                    location.map(|(_, file, line)| format_location(file.as_deref(), *line));
+                println!("{}", verification);
+            }
+            None => result.set_error(format!("unexpected {}", kind)),
+        };
+        result.status = KtestStatus::Faulted;
+        self.abort_ktest(result)
+    }
+
+    // The ktest ran past its time limit or cycle budget, halt it and move on
+    fn hang_event(&mut self, reason: String) -> Result<()> {
+        let mut core = self.session.core(0)?;
+        core.halt(Duration::from_millis(100))?;
+        let pc_val = core.read_core_reg(core.registers().program_counter())?;
+        let cyccnt = read_cyccnt(&mut core)?;
+        let cycle_counter = self.cycle_counter.sample_halted(cyccnt);
+        drop(core);
+        self.running = false;
+
+        let mut result = match self.ktest_result.take() {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        let cycles = cycle_counter.since(&self.klee_trace_start).cycles;
+        println!(
+            "{}: hang at {:#010x}, {} after {} cycles",
+            result.ktest, pc_val, reason, cycles
+        );
+
+        self.record_stack(&mut result);
+        result.end_pc = Some(pc_val);
+        result.over_budget = self
+            .klee_options
+            .cycle_budget
+            .map_or(false, |budget| cycles > budget);
+        result.set_error(format!("{} after {} cycles", reason, cycles));
+        result.status = KtestStatus::Hang;
+        self.abort_ktest(result)
+    }
+
+    // Whether the running ktest is past its time limit or cycle budget
+    fn ktest_limit_exceeded(&self, cycle_counter: &CycleStamp) -> Option<String> {
+        self.ktest_result.as_ref()?;
+        if let Some(timeout) = self.klee_options.timeout {
+            if self.ktest_running_time.elapsed() > timeout {
+                return Some(format!("no bkpt #1 within {:?}", timeout));
+            }
+        }
+        let budget = self.klee_options.cycle_budget?;
+        match cycle_counter.since(&self.klee_trace_start).cycles > budget {
+            true => Some(format!("cycle budget of {} exceeded", budget)),
+            false => None,
+        }
+    }
+
+    // Record the stack of a ktest that didn't reach bkpt #1
+    fn record_stack(
+        &mut self,
+        result: &mut KtestResult,
+    ) -> Vec<(String, Option<String>, Option<u64>)> {
+        let frames = match self.stack_locations() {
+            Ok(frames) => frames,
+            Err(err) => {
+                warn!("Failed to read the stack trace: {:?}", err);
+                vec![]
+            }
+        };
+        result.stack = frames
+            .iter()
+            .map(|(name, file, line)| {
+                format!("{} ({})", name, format_location(file.as_deref(), *line))
+            })
+            .collect();
+        frames
+    }
+
+    // Write the result of a ktest that didn't reach bkpt #1 and reset for the next one
+    fn abort_ktest(&mut self, result: KtestResult) -> Result<()> {
+        self.open_regions.clear();
+        self.regions.clear();
+        self.checkpoints.clear();
+        self.ktest_objects = None;
+        self.ktest_path = None;
+        self.events.push(DebugEvent::KtestFinished {
+            result: result.clone(),
+            done: self.ktests_run as usize,
+            total: self.ktests.len(),
+        });
+        if let Some(results) = self.results.as_mut() {
+            if let Err(e) = results.write(result) {
+                eprintln!("Couldn't write to file: {}", e);
+            }
+        }
+        self.continue_klee_calc(true)?;
+        Ok(())
+    }
+
+    // Decode an injected object with the DWARF type of the variable it was written to. The
+    // variable is the static at the injected address, or for locals the one with the object's name.
+    fn decode_injected_object(&mut self, index: usize) {
+        let object = match self.ktest_result.as_ref().and_then(|r| r.objects.get(index)) {
+            Some(object) => object,
+            None => return,
+        };
+        let dwarf = self.debug_info.dwarf;
+        let value = evaluate_object_at(dwarf, &object.bytes, object.address)
+            .and_then(|value| match value {
+                Some(value) => Ok(Some(value)),
+                None => evaluate_object(
+                    dwarf,
+                    &KtestObject {
+                        name: object.name.clone(),
+                        bytes: object.bytes.clone(),
+                    },
+                ),
+            })
+            .and_then(|value| {
+                value
+                    .map(|value| Variable::from_value(object.name.clone(), &value))
+                    .transpose()
+            });
+        let value = match value {
+            Ok(value) => value.map(|variable| variable.value_to_string()),
+            Err(err) => {
+                warn!("Failed to decode injected object {}: {:?}", object.name, err);
+                None
+            }
+        };
+        if let Some(value) = &value {
+            println!("Value of {}: {}", object.name, value);
+        }
+
+        let object = self.ktest_result.as_mut().and_then(|r| r.objects.get_mut(index));
+        if let Some(object) = object {
+            object.value = value;
+        }
+    }
+
+    // Function name, file and line of every frame of the current stack, innermost first
+    fn stack_locations(&mut self) -> Result<Vec<(String, Option<String>, Option<u64>)>> {
+        self.clear_temporaries();
+        self.set_stack_trace()?;
+        let stack_trace = self.stack_trace.as_ref().unwrap();
+        Ok(stack_trace
+            .iter()
+            .map(|frame| {
+                let file = match (&frame.source.directory, &frame.source.file) {
+                    (Some(dir), Some(file)) => Some(format!("{}/{}", dir, file)),
+                    (None, Some(file)) => Some(file.clone()),
+                    _ => None,
+                };
+                (frame.name.clone(), file, frame.source.line)
+            })
+            .collect())
+    }
+
     fn trace_event(&mut self, _pc_val: u32) -> Result<()> {
         let mut core = self.session.core(0)?;
-        let (pc_val, cycle_counter) = read_cycle_counter(&mut core)?;
-        //println!("pc: {:#010x}, cycle counter: {}", pc_val, cycle_counter);
+        let (pc_val, cyccnt) = read_cycle_counter(&mut core)?;
+        //println!("pc: {:#010x}, cycle counter: {}", pc_val, cyccnt);
+        let cycle_counter = self.cycle_counter.sample_halted(cyccnt);
+
+        if vector_catch_fault(&mut core)? {
+            let exception = ExceptionState::read(&mut core)?;
+            drop(core);
+            return self.fault_event(pc_val, exception.fault_kind(), Some(exception));
+        }
+        let fault = self.fault_handlers.iter().find(|(address, _)| *address == pc_val);
+        if let Some((_, kind)) = fault {
+            let kind = *kind;
+            let exception = match kind {
+                FaultKind::Panic => None,
+                _ => Some(ExceptionState::read(&mut core)?),
+            };
+            drop(core);
+            return self.fault_event(pc_val, kind, exception);
+        }
 
         let nr = read_bkpt(&mut core, pc_val)?;
-        match nr {
+        match self.bkpt_protocol.action(nr).cloned() {
             //end
-            1 => {
+            Some(BkptAction::Stop) => {
                 //println!("Halted on: end");
-                if self.skip {
-                    self.skip = false;
-                } else {
-                    let cycles_since_start = cycle_counter - self.klee_trace_start;
-                    println!("Calculated cycles {}", cycles_since_start);
-                    let mut file = fs::OpenOptions::new()
-                        .write(true)
-                        .append(true)
-                        .open(self.result_filepath.clone())
-                        .unwrap();
-                    let s = format_result_line(self.ktests_run, cycles_since_start);
-                    if let Err(e) = write!(file, "{}", s) {
-                        eprintln!("Couldn't write to file: {}", e);
+                for (label, _) in self.open_regions.drain(..) {
+                    warn!("Region {:?} was never ended", label);
+                }
+                let regions = std::mem::take(&mut self.regions);
+                let checkpoints = std::mem::take(&mut self.checkpoints);
+                //nothing is recorded if no ktest was injected since the start
+                if let Some(mut result) = self.ktest_result.take() {
+                    //objects that no klee call asked for
+                    let unused_objects: Vec<String> = self
+                        .ktest_objects
+                        .take()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|object| object.name)
+                        .collect();
+                    if result.samples.is_empty() {
+                        for name in &unused_objects {
+                            println!("Unused ktest object: {:?}", name);
+                        }
+                        result.unused_objects = unused_objects;
+                    }
+                    result.end_pc = Some(pc_val);
+                    if result.status != KtestStatus::Skipped {
+                        let delta = cycle_counter.since(&self.klee_trace_start);
+                        let cycles_since_start =
+                            delta.without_overhead(self.overhead_cycles.unwrap_or(0));
+                        println!("Calculated cycles {}", cycles_since_start);
+                        if let Some(budget) = self.klee_options.cycle_budget {
+                            if cycles_since_start > budget {
+                                println!("Warning: over the cycle budget of {}", budget);
+                                result.over_budget = true;
+                            }
+                        }
+                        if delta.cycles == 0 {
+                            println!("Warning: the cycle counter is not advancing");
+                        }
+                        for region in &regions {
+                            println!("Calculated cycles for {}: {}", region.label, region.cycles);
+                        }
+                        if delta.may_have_lost_wrap || regions.iter().any(|r| r.may_have_lost_wrap) {
+                            println!(
+                                "Warning: the cycle counter may have wrapped without being noticed"
+                            );
+                        }
+                        result.add_sample(
+                            cycles_since_start,
+                            delta.cycles,
+                            regions,
+                            delta.may_have_lost_wrap,
+                        );
+                        result.add_checkpoints(checkpoints);
+                        if let (Some(painted), Some(bounds)) = (&self.painted_stack, self.stack_bounds) {
+                            let deepest = deepest_stack_write(&mut core, painted)?;
+                            let top = bounds.top.unwrap_or(painted.end);
+                            let usage = top.saturating_sub(deepest.unwrap_or(painted.end)) as u64;
+                            let overflow = deepest == Some(painted.start);
+                            println!("Stack usage {} bytes", usage);
+                            if overflow {
+                                println!(
+                                    "Warning: the stack grew down to its limit at {:#010x}",
+                                    painted.start
+                                );
+                            }
+                            result.add_stack_usage(usage, overflow);
+                        }
+
+                        //run the same ktest again until all samples are taken
+                        if result.samples.len() < self.klee_options.repeat {
+                            self.ktest_result = Some(result);
+                            drop(core);
+                            self.continue_klee_calc(false)?;
+                            return Ok(());
+                        }
+                        if let Some(stats) = &result.sample_stats {
+                            if result.samples.len() > 1 {
+                                println!(
+                                    "Samples: {}, min: {}, max: {}, stddev: {:.1}",
+                                    result.samples.len(),
+                                    stats.min,
+                                    stats.max,
+                                    stats.stddev
+                                );
+                            }
+                        }
+                    }
+                    self.ktest_path = None;
+                    self.events.push(DebugEvent::KtestFinished {
+                        result: result.clone(),
+                        done: self.ktests_run as usize,
+                        total: self.ktests.len(),
+                    });
+                    if let Some(results) = self.results.as_mut() {
+                        if let Err(e) = results.write(result) {
+                            eprintln!("Couldn't write to file: {}", e);
+                        }
                     }
                 }
                 drop(core);
-                self.continue_command()?;
+                self.continue_klee_calc(false)?;
             },
             //start
-            2 => {
-                if self.ktests_run == 0 {
-                    let mut res_folder = self.workdir.clone();
-                    res_folder.push("ktests_results");
-                    fs::create_dir_all(res_folder.as_path())?;
-                    let filename = get_unused_filename(res_folder.to_path_buf());
-                    res_folder.push(filename);
-                    let mut file = File::create(res_folder.clone())?;
+            Some(BkptAction::Start) => {
+                if enable_cycle_counter(&mut core)? {
+                    warn!("Cycle counter was disabled by the firmware, it is now enabled");
+                }
+                if self.results.is_none() {
+                    let res_folder = match &self.klee_options.output {
+                        Some(output) => {
+                            if let Some(parent) = output.parent() {
+                                fs::create_dir_all(parent)?;
+                            }
+                            output.clone()
+                        }
+                        None => {
+                            let mut res_folder = self.workdir.clone();
+                            res_folder.push("ktests_results");
+                            fs::create_dir_all(res_folder.as_path())?;
+                            let filename = get_unused_filename(res_folder.to_path_buf());
+                            res_folder.push(filename);
+                            res_folder
+                        }
+                    };
                     let workdir_str = "Working directory: ".to_string() + self.workdir.to_str().unwrap();
                     let current_dir = env::current_dir()?;
                     let binpath_str = "Bin path: ".to_string() + current_dir.to_str().unwrap() + self.file_path.to_str().unwrap();
-                    file.write_all((workdir_str + "\n" + &binpath_str).as_bytes())?;
-                    self.result_filepath = res_folder;
+                    let mut text_header = workdir_str + "\n" + &binpath_str;
+                    if let Some(overhead) = self.overhead_cycles {
+                        text_header += &format!("\nOverhead: {} cycles subtracted", overhead);
+                    }
+                    if self.klee_options.reset_target {
+                        text_header += "\nTarget reset before every ktest";
+                    }
+                    if self.klee_options.snapshot {
+                        text_header += "\nRAM restored before every ktest";
+                    }
+                    let header = self.result_header();
+                    self.results = Some(ResultFiles::create(res_folder, header, &text_header)?);
                 }
                 //println!("Halted on: start");
-                self.klee_trace_start = cycle_counter;
+                self.klee_trace_start = match self.klee_options.reset_cycle_counter {
+                    true => {
+                        core.write_word_32(DWT_CYCCNT, 0)?;
+                        self.cycle_counter.restart();
+                        self.cycle_counter.sample(0)
+                    }
+                    false => cycle_counter,
+                };
+                self.klee_trace_start_pc = pc_val;
+                self.ktest_running_time.restart();
+                self.open_regions.clear();
+                self.regions.clear();
+                self.checkpoints.clear();
+                if let Some(bounds) = self.stack_bounds {
+                    let sp = core.read_core_reg(core.registers().stack_pointer())?;
+                    self.painted_stack = Some(paint_stack(&mut core, bounds.limit, sp)?);
+                }
                 drop(core);
+                if self.klee_options.snapshot && self.snapshot.is_none() {
+                    let ram = ram_ranges(&self.session.target().memory_map);
+                    let mut core = self.session.core(0)?;
+                    let snapshot = TargetSnapshot::take(&mut core, &ram)?;
+                    println!("Snapshot taken of {} bytes of RAM", snapshot.ram_size());
+                    self.snapshot = Some(snapshot);
+                }
                 self.continue_command()?;
             },
             //klee call
-            3 => {
+            Some(BkptAction::Inject) => {
                 //println!("Halted on: klee call");
-                //get registerfile
-                let reg_file = core.registers();
-                //r0 holds address to klee symbolic variable
-                let reg_address = reg_file.registers().next().unwrap();
-                let klee_var_address = core.read_core_reg(reg_address)?;
-                let mut klee_var_value = vec![0u32; 1];
-                core.read_32(klee_var_address, &mut klee_var_value)?;
-                //ktests directory holds all the ktests
-                let dir = self.ktests_directory.clone();
-                let ktests = fs::read_dir(dir.clone())
-                    .context("Workdirectory does'nt contain ktests folder")?;
-                let validator = Regex::new(&("test0*".to_owned() + &(self.ktests_run + 1).to_string()  + "[.]ktest")).unwrap();
-                let mut no_match = true;
-                for ktest_file in ktests {
-                    let ktest_file = ktest_file.unwrap();
-                    if is_err(dir.clone(), self.ktests_run) {
-                        self.ktests_run += 1;
-                        no_match = false;
-                        self.skip = true;
-                        break;
-                    }
-                    if validator.is_match(ktest_file.file_name().to_str().unwrap()) {
-                        no_match = false;
-                        println!("Running: {}", ktest_file.file_name().to_str().unwrap());
-                        let ktest = parse_ktest(&fs::read(ktest_file.path())?)?;
-                        let mut data = vec![];
-                        //Handle multiple objects? dont think its needed
-                        //println!("{:?}", ktest.objects);
-                        for object in ktest.objects {
-                            let bytes = object.bytes;
-                            let mut d:u32 = 0;
-                            let mut nmbr_shifts = 0;
-                            for byte in bytes {
-                                //bitshift in the bytes to create a u32
-                                d = d | ((byte as u32) << nmbr_shifts * 8);
-                                nmbr_shifts += 1;
-                                //every 4th shift is a finished u32
-                                if nmbr_shifts > 3 {
-                                    nmbr_shifts = 0;
-                                    data.push(d);
-                                    d = 0;
+                //r0 holds address to klee symbolic variable, r1 holds its size in bytes
+                //and r2 holds the address of its name
+                let klee_var_address = read_arg_reg(&mut core, 0)?;
+                let klee_var_size = read_arg_reg(&mut core, 1)? as usize;
+                let klee_var_name_address = read_arg_reg(&mut core, 2)?;
+                let klee_var_name = read_c_string(&mut core, klee_var_name_address)?;
+
+                //the first klee call after a start selects the next ktest
+                if self.ktest_result.is_none() {
+                    let ktest_path = match self.ktests.get(self.ktests_run as usize) {
+                        Some(path) => path.clone(),
+                        None => {
+                            self.trace = false;
+                            self.running = false;
+                            for (address, _) in self.fault_handlers.drain(..) {
+                                core.clear_hw_breakpoint(address)?;
+                            }
+                            set_vector_catch(&mut core, false)?;
+                            println!("kleecalc finished");
+                            if let Some(results) = self.results.as_mut() {
+                                let summaries = results.finish()?;
+                                if summaries.is_empty() {
+                                    println!("No ktests were measured");
+                                }
+                                for summary in summaries {
+                                    println!("{}", summary);
                                 }
                             }
+                            self.events.push(klee_calc_finished_event(&self.results, None));
+                            return Ok(());
                         }
-                        println!("Data being written:");
-                        //data.reverse();
-                        for vec in data.clone() {
-                            print!(" {:#010x}",vec);
-                        }
-                        println!();
-                        core.write_32(klee_var_address, &data)?;
-                        self.ktests_run += 1;
-                        break;
+                    };
+                    let mut result =
+                        KtestResult::new(file_name(&ktest_path), Some(self.klee_trace_start_pc));
+                    let err_file = find_err_file(&self.err_files, &ktest_path);
+                    if let (Some(err_file), true) = (&err_file, self.klee_options.verify_errors) {
+                        let predicted = read_err_file(err_file)?;
+                        println!(
+                            "Running: {}, KLEE predicts {} at {}",
+                            result.ktest,
+                            predicted.kind,
+                            predicted.location()
+                        );
+                        result.verification = Some(ErrorVerification::new(predicted));
+                    } else if let Some(err_file) = err_file {
+                        println!("{} would result in a panic, skipping", file_name(&err_file));
+                        result.status = KtestStatus::Skipped;
+                        self.ktest_objects = Some(vec![]);
+                    } else {
+                        println!("Running: {}", result.ktest);
                     }
+                    self.ktest_result = Some(result);
+                    self.ktest_path = Some(ktest_path);
+                    self.ktests_run += 1;
                 }
-                if no_match {
-                    self.trace = false;
-                    self.running = false; 
-                    println!("kleecalc finished");
+
+                //the objects are read again for every run of the ktest
+                if self.ktest_objects.is_none() {
+                    if let Some(ktest_path) = &self.ktest_path {
+                        self.ktest_objects = Some(read_ktest_objects(ktest_path)?);
+                    }
                 }
-                else {
-                    drop(core);
-                    self.continue_command()?;  
+
+                let result = self.ktest_result.as_mut().unwrap();
+                //what was injected is only recorded for the first run
+                let first_run = result.samples.is_empty();
+                let mut injected = None;
+                if result.status != KtestStatus::Skipped {
+                    //write the ktest object with the same name as the symbolic variable
+                    let objects = self.ktest_objects.get_or_insert_with(Vec::new);
+                    match objects.iter().position(|o| o.name == klee_var_name) {
+                        Some(index) => {
+                            let object = objects.remove(index);
+                            let written = write_ktest_object(
+                                &mut core,
+                                &object,
+                                klee_var_address,
+                                klee_var_size,
+                            );
+                            match written {
+                                Ok(()) => {
+                                    println!(
+                                        "Data being written to {} at {:#010x}:",
+                                        object.name, klee_var_address
+                                    );
+                                    for byte in &object.bytes {
+                                        print!(" {:#04x}", byte);
+                                    }
+                                    println!();
+                                    if first_run {
+                                        result.objects.push(InjectedObject {
+                                            name: object.name,
+                                            address: klee_var_address,
+                                            bytes: object.bytes,
+                                            value: None,
+                                        });
+                                        injected = Some(result.objects.len() - 1);
+                                    }
+                                }
+                                Err(err) => {
+                                    println!("Error: {}", err);
+                                    result.set_error(err.to_string());
+                                }
+                            };
+                        }
+                        None => {
+                            println!(
+                                "No ktest object named {:?}, leaving it unchanged",
+                                klee_var_name
+                            );
+                            if first_run {
+                                result.unknown_symbolics.push(klee_var_name);
+                            }
+                        }
+                    };
+                }
+                drop(core);
+                if let Some(index) = injected {
+                    self.decode_injected_object(index);
                 }
+                self.continue_command()?;
+            },
+            //start of a labelled region, r0 holds the address of the label
+            Some(BkptAction::RegionStart) => {
+                let label_address = read_arg_reg(&mut core, 0)?;
+                let label = read_c_string(&mut core, label_address)?;
+                self.open_regions.push((label, cycle_counter));
+                drop(core);
+                self.continue_command()?;
+            },
+            //end of a labelled region, r0 holds the address of the label
+            Some(BkptAction::RegionEnd) => {
+                let label_address = read_arg_reg(&mut core, 0)?;
+                let label = read_c_string(&mut core, label_address)?;
+                //regions can be nested, end the innermost region with the label
+                match self.open_regions.iter().rposition(|(l, _)| *l == label) {
+                    Some(index) => {
+                        let (label, start) = self.open_regions.remove(index);
+                        let delta = cycle_counter.since(&start);
+                        let overhead = self.overhead_cycles.unwrap_or(0);
+                        add_region_cycles(&mut self.regions, label, &delta, overhead);
+                    }
+                    None => warn!("Region {:?} ended without being started", label),
+                };
+                drop(core);
+                self.continue_command()?;
+            },
+            //cycles since the start under the name of the checkpoint
+            Some(BkptAction::Checkpoint(name)) => {
+                let delta = cycle_counter.since(&self.klee_trace_start);
+                let cycles = delta.without_overhead(self.overhead_cycles.unwrap_or(0));
+                println!("Checkpoint {}: {} cycles since start", name, cycles);
+                self.checkpoints.push(CheckpointCycles { name, cycles });
+                drop(core);
+                self.continue_command()?;
+            },
+            //leave the core halted, tracing goes on when the user continues
+            Some(BkptAction::Halt) => {
+                println!("Halted on: bkpt #{}, continue to go on with kleecalc", nr);
+                drop(core);
+                self.events.push(DebugEvent::Halted {
+                    pc: pc_val,
+                    reason: HaltReason::Breakpoint,
+                    hit_breakpoint_ids: None,
+                });
+            },
+            Some(BkptAction::Ignore) => {
+                drop(core);
+                self.continue_command()?;
             },
             //something else
-            i => {
-                println!("Halted on: bkpt #{}", i);
+            None => {
+                println!("Halted on: bkpt #{}", nr);
                 drop(core);
                 self.continue_command()?;
             },
@@ -1210,12 +2101,6 @@ impl<'a, R: Reader<Offset = usize>> Debugger<'a, R> {
     }
 }
 
-fn format_result_line(ktest: u32, cycles: u32) -> String {
-    let nmbr_of_chars: usize = ((ktest / 10) + 1).try_into().unwrap();
-    "\ntest".to_string() + &"0".repeat(6 - nmbr_of_chars) + &ktest.to_string()
-        + ".ktest: " + &cycles.to_string() + " cycles"
-}
-
 fn get_unused_filename(path: PathBuf) -> String {
     let mut current_max = 0;
     let result_folder = fs::read_dir(path)
@@ -1245,28 +2130,100 @@ fn get_unused_filename(path: PathBuf) -> String {
     (current_max + 1).to_string() + ".cycles"
 }
 
-fn is_err(dir: PathBuf, ktest_run: u32) -> bool {
-    let ktests = fs::read_dir(dir)
-                    .context("Workdirectory does'nt contain ktests folder").unwrap();
-    let err_check = Regex::new(&("test0*".to_owned() + &(ktest_run + 1).to_string()  + "[.].*[.]err")).unwrap();
-    for ktest in ktests {
-        let ktest_file = ktest.unwrap();
-        if err_check.is_match(ktest_file.file_name().to_str().unwrap()) {
-            println!("{} would result in a panic, skipping", ktest_file.file_name().to_str().unwrap());
-            return true;
-        }
+// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xe000edfc;
+const DEMCR_TRCENA: u32 = 1 << 24;
+// DWT Control Register
+const DWT_CTRL: u32 = 0xe0001000;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+// DWT Cycle Counter Register
+const DWT_CYCCNT: u32 = 0xe0001004;
+
+// DEMCR vector catch bits for HardFault, fault escalation, BusFault, UsageFault and MemManage
+const DEMCR_VC_FAULTS: u32 =
+    (1 << 10) | (1 << 9) | (1 << 8) | (1 << 7) | (1 << 6) | (1 << 5) | (1 << 4);
+// Debug Fault Status Register
+const DFSR: u32 = 0xe000ed30;
+// Register number of CONTROL, FAULTMASK, BASEPRI and PRIMASK, with PRIMASK in bit 0
+const PRIMASK_REG: u16 = 0b10100;
+const PRIMASK: u32 = 1 << 0;
+const DFSR_VCATCH: u32 = 1 << 3;
+
+// Halt on entry to the fault exception handlers
+fn set_vector_catch(core: &mut probe_rs::Core, enable: bool) -> Result<(), probe_rs::Error> {
+    let demcr = core.read_word_32(DEMCR)?;
+    let demcr = match enable {
+        true => demcr | DEMCR_VC_FAULTS,
+        false => demcr & !DEMCR_VC_FAULTS,
+    };
+    core.write_word_32(DEMCR, demcr)
+}
+
+// Whether the core halted on entry to a fault handler because of vector catch
+fn vector_catch_fault(core: &mut probe_rs::Core) -> Result<bool, probe_rs::Error> {
+    let dfsr = core.read_word_32(DFSR)?;
+    if dfsr & DFSR_VCATCH == 0 {
+        return Ok(false);
     }
-    false
+    // the flag is sticky and also set by the vector catch of a reset and halt
+    core.write_word_32(DFSR, DFSR_VCATCH)?;
+    let exception = exception_number(core)?;
+    Ok((2..=6).contains(&exception))
 }
 
+// Enable the DWT cycle counter if it isn't already, returns true if it had to be enabled
+fn enable_cycle_counter(core: &mut probe_rs::Core) -> Result<bool, probe_rs::Error> {
+    let mut enabled = false;
+
+    let demcr = core.read_word_32(DEMCR)?;
+    if demcr & DEMCR_TRCENA == 0 {
+        core.write_word_32(DEMCR, demcr | DEMCR_TRCENA)?;
+        enabled = true;
+    }
+
+    let dwt_ctrl = core.read_word_32(DWT_CTRL)?;
+    if dwt_ctrl & DWT_CTRL_CYCCNTENA == 0 {
+        core.write_word_32(DWT_CTRL, dwt_ctrl | DWT_CTRL_CYCCNTENA)?;
+        enabled = true;
+    }
+
+    Ok(enabled)
+}
 
 // Read the cycle counter from the DWT Cycle Counter Register (0xe0001004)
 fn read_cycle_counter(core: &mut probe_rs::Core) -> Result<(u32, u32), probe_rs::Error> {
-    let mut buff: Vec<u32> = vec![0; 1];
-    core.read_32(0xe0001004, &mut buff)?;
+    let cyccnt = read_cyccnt(core)?;
     let pc = core.registers().program_counter();
     let pc_val = core.read_core_reg(pc)?;
-    Ok((pc_val, buff[0]))
+    Ok((pc_val, cyccnt))
+}
+
+// Read only the cycle counter, this also works while the core is running
+fn read_cyccnt(core: &mut probe_rs::Core) -> Result<u32, probe_rs::Error> {
+    let mut buff: Vec<u32> = vec![0; 1];
+    core.read_32(DWT_CYCCNT, &mut buff)?;
+    Ok(buff[0])
+}
+
+// Run the bkpt #2/bkpt #1 pair at `address` and return the cycles between the two halts
+fn measure_empty_region(core: &mut probe_rs::Core, address: u32) -> Result<u64> {
+    let pc = core.registers().program_counter();
+    core.write_core_reg(pc.into(), address)?;
+    core.run()?;
+    core.wait_for_core_halted(Duration::from_millis(100))?;
+    let start = read_cyccnt(core)?;
+
+    core.write_core_reg(pc.into(), address + 2)?;
+    core.run()?;
+    core.wait_for_core_halted(Duration::from_millis(100))?;
+    let end = read_cyccnt(core)?;
+
+    Ok(end.wrapping_sub(start) as u64)
+}
+
+// Read argument register r0..r3
+fn read_arg_reg(core: &mut probe_rs::Core, nr: u16) -> Result<u32, probe_rs::Error> {
+    core.read_core_reg(nr)
 }
 
 // Retrieve the breakpoint number
@@ -1433,6 +2390,26 @@ impl Variable {
         return Ok(variable);
     }
 
+    // A variable holding an evaluated value that is not read from the target
+    pub fn from_value<R: Reader<Offset = usize>>(
+        name: String,
+        value: &EvaluatorValue<R>,
+    ) -> Result<Variable> {
+        let mut variable = Variable {
+            id: 0,
+            name: Some(name),
+            value: "This should be overwritten with the correct value".to_string(),
+            type_: "".to_owned(),
+            source: None,
+            kind: VariableKind::Unknown,
+            children: vec![],
+        };
+
+        variable.evaluate(value, &None)?;
+
+        Ok(variable)
+    }
+
     fn evaluate<R: Reader<Offset = usize>>(
         &mut self,
         value: &EvaluatorValue<R>,
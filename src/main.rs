@@ -1,3 +1,4 @@
+mod batch;
 mod cli;
 mod commands;
 mod debug_adapter;
@@ -23,6 +24,8 @@ use anyhow::{anyhow, Context, Result};
 
 use std::str::FromStr;
 
+use debugger::kleecalc_diff::{diff_result_files, Threshold};
+
 use chrono::Local;
 use env_logger::*;
 use log::{error, LevelFilter};
@@ -86,6 +89,58 @@ pub struct Opt {
         default_value = "8800"
     )]
     port: u16,
+
+    #[structopt(subcommand)]
+    command: Option<SubCommand>,
+}
+
+#[derive(Debug, StructOpt)]
+enum SubCommand {
+    /// Compare two kleecalc result files and exit with an error if a ktest got slower
+    KleecalcDiff {
+        /// Old result file (.cycles or .json)
+        old: PathBuf,
+
+        /// New result file (.cycles or .json)
+        new: PathBuf,
+
+        /// Allowed slowdown in cycles, or in percent with a trailing %
+        #[structopt(short = "t", long = "threshold", default_value = "0")]
+        threshold: Threshold,
+    },
+
+    /// Run kleecalc on all ktests without the prompt. The exit status is 2 if ktests were
+    /// skipped, 4 if ktests faulted, 8 if ktests hung or went over the cycle budget and 16 if
+    /// ktests couldn't be run, or:ed together
+    Kleecalc {
+        /// Elf file path
+        #[structopt(long = "elf-file")]
+        elf_file_path: PathBuf,
+
+        /// Type of Chip
+        #[structopt(short = "c", long = "chip")]
+        chip: String,
+
+        /// Probe number
+        #[structopt(long = "probe", default_value = "0")]
+        probe_num: usize,
+
+        /// Ktest directory
+        #[structopt(short = "k", long = "ktests-directory")]
+        ktests_directory: PathBuf,
+
+        /// Results file (.cycles), the .json and .csv files are written next to it
+        #[structopt(short = "o", long = "output")]
+        output: PathBuf,
+
+        /// Flash the elf file before measuring
+        #[structopt(long = "flash")]
+        flash: bool,
+
+        /// kleecalc options, e.g. -- --snapshot --timeout 2
+        #[structopt(last = true)]
+        klee_calc_args: Vec<String>,
+    },
 }
 
 fn main() -> Result<()> {
@@ -117,12 +172,56 @@ fn main() -> Result<()> {
         .filter_module("probe_rs", probe_rs_log_level)
         .init();
 
+    if let Some(command) = opt.command {
+        return run_subcommand(command);
+    }
+
     match opt.mode {
         Mode::Debug => cli::debug_mode(opt),
         Mode::DebugAdapter => debug_adapter::start_tcp_server(opt.port),
     }
 }
 
+fn run_subcommand(command: SubCommand) -> Result<()> {
+    match command {
+        SubCommand::KleecalcDiff {
+            old,
+            new,
+            threshold,
+        } => {
+            let diff = diff_result_files(&old, &new, threshold)?;
+            println!("{}", diff);
+            if diff.is_regression() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        SubCommand::Kleecalc {
+            elf_file_path,
+            chip,
+            probe_num,
+            ktests_directory,
+            output,
+            flash,
+            klee_calc_args,
+        } => {
+            let status = batch::batch_mode(batch::BatchConfig {
+                elf_file_path,
+                chip,
+                probe_num,
+                ktests_directory,
+                output,
+                flash,
+                klee_calc_args,
+            })?;
+            if status != 0 {
+                std::process::exit(status);
+            }
+            Ok(())
+        }
+    }
+}
+
 fn attach_probe(chip: &str, probe_num: usize) -> Result<Session> {
     // Get a list of all available debug probes.
     let probes = Probe::list_all();